@@ -0,0 +1,7 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate pwsf;
+
+fuzz_target!(|data: &[u8]| {
+    pwsf::fuzz::unlock(data);
+});
@@ -1,5 +1,13 @@
 extern crate gcrypt;
 
+use super::Algorithm;
+use error::Error;
+use secret::SecretKey;
+
+fn crypto_error(e: gcrypt::Error) -> Error {
+    return Error::Crypto(e.to_string());
+}
+
 pub struct HMAC {
     mac: self::gcrypt::mac::Mac,
 }
@@ -18,8 +26,11 @@ impl HMAC {
         self.mac.update(data).expect("Can't update HMAC");
     }
 
-    pub fn verify(&mut self, expected: &[u8]) -> Result<(), gcrypt::Error> {
-        return self.mac.verify(expected);
+    pub fn verify(&mut self, expected: &[u8]) -> Result<(), Error> {
+        if !super::ct_eq(&self.get_mac(), expected) {
+            return Err(Error::HmacMismatch);
+        }
+        return Ok(());
     }
 
     pub fn get_mac(&mut self) -> [u8; 32] {
@@ -30,7 +41,26 @@ impl HMAC {
 }
 
 pub fn init() {
-    gcrypt::init(|x| { x.disable_secmem(); });
+    // keys and digests handled by libgcrypt itself live in its locked pool
+    gcrypt::init(|x| { x.enable_secmem(32768).expect("Can't initialize secure memory"); });
+}
+
+pub fn hmac(alg: Algorithm, key: &[u8], data: &[u8]) -> Vec<u8> {
+    use self::gcrypt::mac::{Mac, Algorithm as MacAlgorithm};
+
+    let (a, len) = match alg {
+        Algorithm::Sha1 => (MacAlgorithm::HmacSha1, 20),
+        Algorithm::Sha256 => (MacAlgorithm::HmacSha256, 32),
+        Algorithm::Sha512 => (MacAlgorithm::HmacSha512, 64),
+    };
+
+    let mut h = Mac::new(a).expect("Can't initialize HMAC");
+    h.set_key(key).expect("Can't set HMAC key");
+    h.update(data).expect("Can't update HMAC");
+
+    let mut output = vec![0; len];
+    h.get_mac(&mut output).expect("Can't compute HMAC");
+    return output;
 }
 
 pub fn sha256(input: &[u8]) -> [u8; 32] {
@@ -45,7 +75,7 @@ pub fn sha256(input: &[u8]) -> [u8; 32] {
     return output;
 }
 
-pub fn stretch(password: &str, salt: &[u8], iter: u32) -> [u8; 32] {
+pub fn stretch(password: &str, salt: &[u8], iter: u32) -> SecretKey {
     use self::gcrypt::digest::{MessageDigest,Algorithm};
 
     let mut h = MessageDigest::new(Algorithm::Sha256).expect("Can't initialize SHA256");
@@ -53,40 +83,40 @@ pub fn stretch(password: &str, salt: &[u8], iter: u32) -> [u8; 32] {
     h.update(salt);
     h.finish();
 
-    let mut b: [u8; 32] = [0; 32];
+    let mut b = SecretKey::new();
     b.copy_from_slice(h.get_only_digest().expect("Can't get SHA256 digest"));
 
     for _ in 0..iter {
         h.reset();
-        h.update(&b);
+        h.update(&b[..]);
         b.copy_from_slice(h.get_only_digest().expect("Can't get SHA256 digest"));
     }
 
-    return b.clone();
+    return b;
 }
 
-pub fn decrypt_block_ecb(block: &[u8], key: &[u8]) -> Result<[u8; 32], gcrypt::Error> {
+pub fn decrypt_block_ecb(block: &[u8], key: &[u8]) -> Result<SecretKey, Error> {
     use self::gcrypt::cipher::{Cipher, Algorithm, Mode};
 
-    let mut ct: [u8; 32] = [0; 32];
+    let mut pt = SecretKey::new();
     let mut c = Cipher::new(Algorithm::Twofish, Mode::Ecb).expect("Can't initialize ECB Twofish");
     c.set_key(&key).expect("Can't set ECB Twofish key");
-    return match c.decrypt(&block, &mut ct) {
-        Ok(_) => Ok(ct.clone()),
-        Err(e) => Err(e),
+    return match c.decrypt(&block, &mut pt[..]) {
+        Ok(_) => Ok(pt),
+        Err(e) => Err(crypto_error(e)),
     };
 }
 
-pub fn decrypt_inplace(data: &mut [u8], key: &[u8], iv: &[u8]) -> Result<(), gcrypt::Error> {
+pub fn decrypt_inplace(data: &mut [u8], key: &[u8], iv: &[u8]) -> Result<(), Error> {
     use self::gcrypt::cipher::{Cipher, Algorithm, Mode};
 
     let mut c = Cipher::new(Algorithm::Twofish, Mode::Cbc).expect("Can't initialize CBC Twofish");
     c.set_iv(iv).expect("Can't set CBC Twofish IV");
     c.set_key(key).expect("Can't set CBC Twofish key");
-    return c.decrypt_inplace(data);
+    return c.decrypt_inplace(data).map_err(crypto_error);
 }
 
-pub fn encrypt_block_ecb(block: &[u8], key: &[u8]) -> Result<[u8; 32], gcrypt::Error> {
+pub fn encrypt_block_ecb(block: &[u8], key: &[u8]) -> Result<[u8; 32], Error> {
     use self::gcrypt::cipher::{Cipher, Algorithm, Mode};
 
     let mut ct: [u8; 32] = [0; 32];
@@ -94,15 +124,15 @@ pub fn encrypt_block_ecb(block: &[u8], key: &[u8]) -> Result<[u8; 32], gcrypt::E
     c.set_key(&key).expect("Can't set ECB Twofish key");
     return match c.encrypt(&block, &mut ct) {
         Ok(_) => Ok(ct.clone()),
-        Err(e) => Err(e),
+        Err(e) => Err(crypto_error(e)),
     };
 }
 
-pub fn encrypt_inplace(data: &mut [u8], key: &[u8], iv: &[u8]) -> Result<(), gcrypt::Error> {
+pub fn encrypt_inplace(data: &mut [u8], key: &[u8], iv: &[u8]) -> Result<(), Error> {
     use self::gcrypt::cipher::{Cipher, Algorithm, Mode};
 
     let mut c = Cipher::new(Algorithm::Twofish, Mode::Cbc).expect("Can't initialize CBC Twofish");
     c.set_iv(iv).expect("Can't set CBC Twofish IV");
     c.set_key(key).expect("Can't set CBC Twofish key");
-    return c.encrypt_inplace(data);
+    return c.encrypt_inplace(data).map_err(crypto_error);
 }
@@ -0,0 +1,45 @@
+// Twofish, SHA-256 and HMAC-SHA1/256/512 either from libgcrypt ("gcrypt"
+// feature) or from the pure Rust RustCrypto crates ("rustcrypto" feature,
+// the default). Both backends expose the same set of functions, gcrypt is
+// preferred when both are enabled.
+
+#[cfg(feature = "gcrypt")]
+pub mod libgcrypt;
+
+#[cfg(feature = "rustcrypto")]
+pub mod rustcrypto;
+
+#[cfg(feature = "gcrypt")]
+pub use self::libgcrypt::*;
+
+#[cfg(all(feature = "rustcrypto", not(feature = "gcrypt")))]
+pub use self::rustcrypto::*;
+
+#[cfg(not(any(feature = "gcrypt", feature = "rustcrypto")))]
+compile_error!("either \"rustcrypto\" or \"gcrypt\" feature has to be enabled");
+
+use std::ptr;
+
+/// Hash function of an HMAC, see `hmac`.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+// Compares secrets (password hashes, MACs, passwords) in time that only
+// depends on their length, which isn't secret.
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    // volatile reads keep the compiler from turning the loop into an
+    // early exit
+    let mut diff: u8 = 0;
+    for i in 0..a.len() {
+        diff |= unsafe { ptr::read_volatile(&a[i]) ^ ptr::read_volatile(&b[i]) };
+    }
+    return diff == 0;
+}
@@ -0,0 +1,157 @@
+extern crate twofish;
+extern crate sha1;
+extern crate sha2;
+extern crate hmac;
+
+use self::twofish::Twofish;
+use self::twofish::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use self::twofish::cipher::generic_array::GenericArray;
+use self::sha1::Sha1;
+use self::sha2::{Digest, Sha256, Sha512};
+use self::hmac::{Hmac, Mac};
+
+use super::Algorithm;
+use error::Error;
+use secret::SecretKey;
+
+const BLOCK: usize = 16;
+
+pub struct HMAC {
+    mac: Hmac<Sha256>,
+}
+
+impl HMAC {
+    pub fn new(key: &[u8]) -> HMAC {
+        let h = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("Can't set HMAC key");
+        return HMAC {mac: h};
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.mac.update(data);
+    }
+
+    pub fn verify(&mut self, expected: &[u8]) -> Result<(), Error> {
+        if !super::ct_eq(&self.get_mac(), expected) {
+            return Err(Error::HmacMismatch);
+        }
+        return Ok(());
+    }
+
+    pub fn get_mac(&mut self) -> [u8; 32] {
+        let mut output: [u8; 32] = [0; 32];
+        output.copy_from_slice(&self.mac.clone().finalize().into_bytes());
+        return output;
+    }
+}
+
+pub fn init() {
+}
+
+pub fn hmac(alg: Algorithm, key: &[u8], data: &[u8]) -> Vec<u8> {
+    match alg {
+        Algorithm::Sha1 => {
+            let mut h = <Hmac<Sha1> as Mac>::new_from_slice(key).expect("Can't set HMAC key");
+            h.update(data);
+            return h.finalize().into_bytes().to_vec();
+        },
+        Algorithm::Sha256 => {
+            let mut h = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("Can't set HMAC key");
+            h.update(data);
+            return h.finalize().into_bytes().to_vec();
+        },
+        Algorithm::Sha512 => {
+            let mut h = <Hmac<Sha512> as Mac>::new_from_slice(key).expect("Can't set HMAC key");
+            h.update(data);
+            return h.finalize().into_bytes().to_vec();
+        },
+    }
+}
+
+pub fn sha256(input: &[u8]) -> [u8; 32] {
+    let mut output: [u8; 32] = [0; 32];
+    output.copy_from_slice(&Sha256::digest(input));
+    return output;
+}
+
+pub fn stretch(password: &str, salt: &[u8], iter: u32) -> SecretKey {
+    let mut h = Sha256::new();
+    h.update(password.as_bytes());
+    h.update(salt);
+
+    let mut b = SecretKey::new();
+    b.copy_from_slice(&h.finalize());
+
+    for _ in 0..iter {
+        let d = Sha256::digest(&b[..]);
+        b.copy_from_slice(&d);
+    }
+
+    return b;
+}
+
+fn cipher(key: &[u8]) -> Result<Twofish, Error> {
+    return Twofish::new_from_slice(key).map_err(|_| Error::Crypto("Invalid Twofish key length".to_string()));
+}
+
+fn check_blocks(data: &[u8]) -> Result<(), Error> {
+    if data.len() % BLOCK != 0 {
+        return Err(Error::Crypto("Data is not a multiple of the Twofish block size".to_string()));
+    }
+    return Ok(());
+}
+
+pub fn decrypt_block_ecb(block: &[u8], key: &[u8]) -> Result<SecretKey, Error> {
+    let c = cipher(key)?;
+
+    let mut pt = SecretKey::new();
+    pt.copy_from_slice(block);
+    for b in pt.chunks_mut(BLOCK) {
+        c.decrypt_block(GenericArray::from_mut_slice(b));
+    }
+    return Ok(pt);
+}
+
+pub fn decrypt_inplace(data: &mut [u8], key: &[u8], iv: &[u8]) -> Result<(), Error> {
+    let c = cipher(key)?;
+    check_blocks(data)?;
+
+    let mut prev: [u8; BLOCK] = [0; BLOCK];
+    prev.copy_from_slice(iv);
+    for b in data.chunks_mut(BLOCK) {
+        let mut ct: [u8; BLOCK] = [0; BLOCK];
+        ct.copy_from_slice(b);
+        c.decrypt_block(GenericArray::from_mut_slice(b));
+        for i in 0..BLOCK {
+            b[i] ^= prev[i];
+        }
+        prev = ct;
+    }
+    return Ok(());
+}
+
+pub fn encrypt_block_ecb(block: &[u8], key: &[u8]) -> Result<[u8; 32], Error> {
+    let c = cipher(key)?;
+
+    let mut ct: [u8; 32] = [0; 32];
+    ct.copy_from_slice(block);
+    for b in ct.chunks_mut(BLOCK) {
+        c.encrypt_block(GenericArray::from_mut_slice(b));
+    }
+    return Ok(ct);
+}
+
+pub fn encrypt_inplace(data: &mut [u8], key: &[u8], iv: &[u8]) -> Result<(), Error> {
+    let c = cipher(key)?;
+    check_blocks(data)?;
+
+    let mut prev: [u8; BLOCK] = [0; BLOCK];
+    prev.copy_from_slice(iv);
+    for b in data.chunks_mut(BLOCK) {
+        for i in 0..BLOCK {
+            b[i] ^= prev[i];
+        }
+        c.encrypt_block(GenericArray::from_mut_slice(b));
+        prev.copy_from_slice(b);
+    }
+    return Ok(());
+}
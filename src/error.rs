@@ -0,0 +1,105 @@
+use std;
+use std::fmt;
+use std::io;
+
+use lock;
+
+/// Everything that can go wrong while opening or saving a database.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    // file doesn't start with the PWS3 tag
+    BadTag,
+    // stretched password doesn't match the one stored in the file
+    WrongPassword,
+    // file or record ended earlier than expected
+    Truncated,
+    // EOF marker is not right before the HMAC at the end of the file
+    MissingEof,
+    // encrypted data is not a multiple of the cipher block size
+    BadLength,
+    // HMAC of the decrypted data doesn't match the stored one
+    HmacMismatch,
+    // record at the given offset of the decrypted data can't be parsed
+    CorruptField { offset: u64 },
+    // entry query is not a valid regular expression
+    InvalidPattern(String),
+    // database is locked by somebody else
+    Locked(lock::Owner),
+    // database lock was left behind by a process that is no longer running
+    StaleLock(lock::Owner),
+    // database was opened read-only and can't be saved
+    ReadOnly,
+    // database was changed on disk since it was opened
+    Modified,
+    // password can't be generated with the given policy
+    InvalidPolicy(String),
+    // otpauth:// URI can't be imported
+    InvalidOtpUri(String),
+    // alias or shortcut refers to an entry that doesn't exist
+    BrokenReference(String),
+    // aliases or shortcuts refer to each other
+    ReferenceCycle(String),
+    Io(io::Error),
+    Crypto(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::BadTag => write!(f, "Not a Password Safe V3 database"),
+            Error::WrongPassword => write!(f, "Invalid password"),
+            Error::Truncated => write!(f, "Database is truncated"),
+            Error::MissingEof => write!(f, "Database has no EOF marker at the expected position"),
+            Error::BadLength => write!(f, "Encrypted data is not a multiple of the block size"),
+            Error::HmacMismatch => write!(f, "Database integrity check failed"),
+            Error::CorruptField { offset } => write!(f, "Corrupted field at offset {}", offset),
+            Error::InvalidPattern(ref e) => write!(f, "Invalid pattern: {}", e),
+            Error::Locked(ref o) => write!(f, "Database is locked by {}", o),
+            Error::StaleLock(ref o) => write!(f, "Database has a stale lock of {}", o),
+            Error::ReadOnly => write!(f, "Database is opened read-only"),
+            Error::Modified => write!(f, "Database was changed on disk since it was opened"),
+            Error::InvalidPolicy(ref e) => write!(f, "Invalid password policy: {}", e),
+            Error::InvalidOtpUri(ref e) => write!(f, "Invalid OTP URI: {}", e),
+            Error::BrokenReference(ref r) => write!(f, "Referenced entry {} doesn't exist", r),
+            Error::ReferenceCycle(ref n) => write!(f, "Entry {} refers to itself", n),
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Crypto(ref e) => write!(f, "Crypto error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::BadTag => "bad tag",
+            Error::WrongPassword => "wrong password",
+            Error::Truncated => "truncated",
+            Error::MissingEof => "missing EOF marker",
+            Error::BadLength => "bad length",
+            Error::HmacMismatch => "HMAC mismatch",
+            Error::CorruptField { .. } => "corrupted field",
+            Error::InvalidPattern(_) => "invalid pattern",
+            Error::Locked(_) => "locked",
+            Error::StaleLock(_) => "stale lock",
+            Error::ReadOnly => "read-only",
+            Error::Modified => "modified",
+            Error::InvalidPolicy(_) => "invalid policy",
+            Error::InvalidOtpUri(_) => "invalid OTP URI",
+            Error::BrokenReference(_) => "broken reference",
+            Error::ReferenceCycle(_) => "reference cycle",
+            Error::Io(_) => "I/O error",
+            Error::Crypto(_) => "crypto error",
+        }
+    }
+}
+
+// Short reads only happen when the database ends too early.
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => Error::Truncated,
+            _ => Error::Io(e),
+        }
+    }
+}
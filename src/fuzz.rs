@@ -0,0 +1,97 @@
+// Entry points of the cargo-fuzz targets (see fuzz/), none of them may
+// panic on any input.
+
+use std::io::Cursor;
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crypto;
+use item;
+use keychain;
+
+const PASSWORD: &str = "fuzz";
+
+pub fn parse_field(data: &[u8]) {
+    crypto::init();
+
+    let mut mac = crypto::HMAC::new(&[0; 32]);
+    let mut c = Cursor::new(data);
+    loop {
+        match item::parse_field(&mut mac, &item::DATA, &mut c) {
+            Ok(Some(_)) => (),
+            _ => break,
+        }
+    }
+}
+
+pub fn parse(data: &[u8]) {
+    crypto::init();
+
+    let mut mac = crypto::HMAC::new(&[0; 32]);
+    let mut c = Cursor::new(data);
+    match item::parse(&mut mac, &item::HEADER, &mut c) {
+        Ok(Some(_)) => (),
+        _ => return,
+    }
+    loop {
+        match item::parse(&mut mac, &item::DATA, &mut c) {
+            Ok(Some(_)) => (),
+            _ => break,
+        }
+    }
+}
+
+// Odd first byte: the rest is used as decrypted data and wrapped into a
+// valid envelope so that the fuzzer can reach the record parser.
+// Even first byte: the rest is used as the whole file, with the number of
+// password iterations capped to keep every run fast.
+pub fn unlock(data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+
+    let file = if data[0] & 1 == 1 {
+        envelope(&data[1..])
+    } else {
+        let mut d = data[1..].to_vec();
+        if d.len() >= 40 {
+            d[36] = 0;
+            d[37] &= 0x0f;
+            d[38] = 0;
+            d[39] = 0;
+        }
+        d
+    };
+
+    let _ = keychain::V3::from_reader(&file[..], PASSWORD);
+}
+
+fn envelope(plain: &[u8]) -> Vec<u8> {
+    crypto::init();
+
+    let salt: [u8; 32] = [0; 32];
+    let k: [u8; 32] = [1; 32];
+    let l: [u8; 32] = [2; 32];
+    let iv: [u8; 16] = [3; 16];
+    let iter = 1;
+
+    let stretched = crypto::stretch(PASSWORD, &salt, iter);
+
+    let mut f = Vec::new();
+    f.extend_from_slice(b"PWS3");
+    f.extend_from_slice(&salt);
+    f.write_u32::<LittleEndian>(iter).unwrap();
+    f.extend_from_slice(&crypto::sha256(&stretched[..]));
+    f.extend_from_slice(&crypto::encrypt_block_ecb(&k, &stretched[..]).unwrap());
+    f.extend_from_slice(&crypto::encrypt_block_ecb(&l, &stretched[..]).unwrap());
+    f.extend_from_slice(&iv);
+
+    let mut d = plain.to_vec();
+    let n = d.len();
+    d.resize(n + (16 - n % 16) % 16, 0);
+    crypto::encrypt_inplace(&mut d, &k, &iv).unwrap();
+    f.extend_from_slice(&d);
+
+    f.extend_from_slice(b"PWS3-EOFPWS3-EOF");
+    f.extend_from_slice(&[0; 32]);
+    return f;
+}
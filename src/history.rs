@@ -0,0 +1,101 @@
+// Password history (field 0x0f) is stored as text: "fmmnn" where f is 0
+// or 1 (enabled), mm is the maximum and nn the current number of entries
+// (2 hex digits each) followed by nn times "TTTTTTTTLLLLpassword", time of
+// the change (8 hex digits), length of the password in characters (4 hex
+// digits) and the password itself. Oldest entries come first.
+
+use item::{Data, Time};
+use secret::SecretString;
+
+/// One of the previous passwords of an entry.
+#[derive(Debug,Clone,PartialEq)]
+pub struct OldPassword {
+    /// when the password was set
+    pub time: Time,
+    pub password: SecretString,
+}
+
+/// Previous passwords of an entry, oldest first.
+#[derive(Debug,Clone,PartialEq)]
+pub struct PasswordHistory {
+    pub enabled: bool,
+    pub max: usize,
+    pub entries: Vec<OldPassword>,
+}
+
+impl PasswordHistory {
+    pub fn new(max: usize) -> PasswordHistory {
+        return PasswordHistory{enabled: true, max, entries: Vec::new()};
+    }
+
+    /// Parses the Password Safe representation, returns None if it's
+    /// malformed.
+    pub fn parse(s: &str) -> Option<PasswordHistory> {
+        let mut c = s.chars();
+
+        let enabled = match hex(&mut c, 1)? {
+            0 => false,
+            1 => true,
+            _ => return None,
+        };
+        let max = hex(&mut c, 2)? as usize;
+        let count = hex(&mut c, 2)?;
+
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let time = Time::from_secs(hex(&mut c, 8)? as u64);
+            let len = hex(&mut c, 4)?;
+
+            let mut password = String::new();
+            for _ in 0..len {
+                password.push(c.next()?);
+            }
+            entries.push(OldPassword{time, password: SecretString::new(password)});
+        }
+
+        if c.next().is_some() {
+            return None;
+        }
+        return Some(PasswordHistory{enabled, max, entries});
+    }
+
+    /// Records a previous password, dropping the oldest ones beyond `max`.
+    /// Nothing is recorded when the history is disabled.
+    pub fn push(&mut self, time: Time, password: SecretString) {
+        if !self.enabled {
+            return;
+        }
+
+        self.entries.push(OldPassword{time, password});
+        while self.entries.len() > self.max {
+            self.entries.remove(0);
+        }
+    }
+
+    /// Field contents in the Password Safe representation.
+    pub fn to_data(&self) -> Data {
+        // the format has no room for more than 255 entries
+        let max = if self.max > 0xff { 0xff } else { self.max };
+        let skip = if self.entries.len() > 0xff { self.entries.len() - 0xff } else { 0 };
+
+        // no reallocations, they would leave copies of passwords behind
+        let len = self.entries.iter().skip(skip).fold(5, |len, e| len + 12 + e.password.len());
+        let mut s = String::with_capacity(len);
+
+        s.push_str(&format!("{:01x}{:02x}{:02x}", self.enabled as u8, max, self.entries.len() - skip));
+        for e in self.entries.iter().skip(skip) {
+            let len = e.password.chars().count();
+            s.push_str(&format!("{:08x}{:04x}", e.time.secs() as u32, len));
+            s.push_str(&e.password);
+        }
+        return Data::Text(s);
+    }
+}
+
+pub(crate) fn hex(c: &mut Iterator<Item=char>, digits: usize) -> Option<u32> {
+    let mut v: u32 = 0;
+    for _ in 0..digits {
+        v = (v << 4) | c.next()?.to_digit(16)?;
+    }
+    return Some(v);
+}
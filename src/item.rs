@@ -1,16 +1,31 @@
 use std;
+use std::fmt;
 use std::io::Cursor;
-use std::io::SeekFrom;
 use std::io::Read;
 use std::io::prelude::*;
 use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
 use byteorder::{WriteBytesExt, ReadBytesExt, LittleEndian};
+use rand::{self, Rng};
+use libc;
 
 use crypto;
-
+use error::Error;
+use history::PasswordHistory;
+use otp::Totp;
+use policy::PasswordPolicy;
+use reference::Reference;
+use secret;
+
+/// Type of a field, the same kind may have different on-disk type bytes
+/// in the header and in entries.
 #[derive(PartialEq,Eq,Hash,Debug,Clone,Copy)]
+#[non_exhaustive]
 pub enum Kind {
-    Unknown,
+    // raw field type byte of a field we don't know how to decode
+    Unknown(u8),
     Version,
     UUID,
     End,
@@ -32,58 +47,136 @@ pub enum Kind {
     RunCommand,
     Protected,
     Email,
+    PasswordModifyTime,
+    URL,
+    PasswordExpiryInterval,
+    PasswordPolicyName,
+    KeyboardShortcut,
+    AttachmentRef,
+    TwoFactorKey,
+    CreditCardNumber,
+    CreditCardExpiration,
+    CreditCardVerifValue,
+    CreditCardPin,
+    QRCode,
+    TotpConfig,
+    TotpLength,
+    TotpTimeStep,
+    TotpStartTime,
+
+    // header
+    NonDefaultPrefs,
+    TreeDisplayStatus,
+    LastSaveTime,
+    LastSaveWho,
+    LastSaveWhat,
+    LastSaveUser,
+    LastSaveHost,
+    DbName,
+    DbDescription,
+    DbFilters,
+    RecentlyUsed,
+    NamedPasswordPolicies,
+    EmptyGroups,
+    Yubico,
+    LastPasswordChange,
+}
+
+impl Kind {
+    /// Whether fields of this kind are kept encrypted in memory, see
+    /// `Data::Sealed`.
+    pub fn is_sensitive(&self) -> bool {
+        match *self {
+            Kind::Password | Kind::PasswordHistory | Kind::Notes => true,
+            Kind::TwoFactorKey => true,
+            Kind::CreditCardNumber | Kind::CreditCardVerifValue | Kind::CreditCardPin => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(PartialEq,Eq,Hash,Debug,Clone,Copy)]
-pub enum Type {
+pub(crate) enum Type {
     Raw,
     Byte,
     Short,
     Int,
     Text,
+    Time,
 }
 
 #[derive(Debug,Clone)]
-pub struct Def {
+pub(crate) struct Def {
     pub kind: Kind,
     pub tp: Type,
 }
 
 lazy_static! {
-    pub static ref HEADER: HashMap<u8,Def> = {
+    pub(crate) static ref HEADER: HashMap<u8,Def> = {
         let mut m = HashMap::new();
-        m.insert(0x00, Def{kind: Kind::Version, tp: Type::Short });
-        m.insert(0x01, Def{kind: Kind::UUID,    tp: Type::Raw   });
-        m.insert(0xff, Def{kind: Kind::End,     tp: Type::Raw   });
+        m.insert(0x00, Def{kind: Kind::Version,               tp: Type::Short });
+        m.insert(0x01, Def{kind: Kind::UUID,                  tp: Type::Raw   });
+        m.insert(0x02, Def{kind: Kind::NonDefaultPrefs,       tp: Type::Text  });
+        m.insert(0x03, Def{kind: Kind::TreeDisplayStatus,     tp: Type::Text  });
+        m.insert(0x04, Def{kind: Kind::LastSaveTime,          tp: Type::Time  });
+        m.insert(0x05, Def{kind: Kind::LastSaveWho,           tp: Type::Text  });
+        m.insert(0x06, Def{kind: Kind::LastSaveWhat,          tp: Type::Text  });
+        m.insert(0x07, Def{kind: Kind::LastSaveUser,          tp: Type::Text  });
+        m.insert(0x08, Def{kind: Kind::LastSaveHost,          tp: Type::Text  });
+        m.insert(0x09, Def{kind: Kind::DbName,                tp: Type::Text  });
+        m.insert(0x0a, Def{kind: Kind::DbDescription,         tp: Type::Text  });
+        m.insert(0x0b, Def{kind: Kind::DbFilters,             tp: Type::Text  });
+        m.insert(0x0f, Def{kind: Kind::RecentlyUsed,          tp: Type::Text  });
+        m.insert(0x10, Def{kind: Kind::NamedPasswordPolicies, tp: Type::Text  });
+        m.insert(0x11, Def{kind: Kind::EmptyGroups,           tp: Type::Text  });
+        m.insert(0x12, Def{kind: Kind::Yubico,                tp: Type::Raw   });
+        m.insert(0x13, Def{kind: Kind::LastPasswordChange,    tp: Type::Time  });
+        m.insert(0xff, Def{kind: Kind::End,                   tp: Type::Raw   });
         m
     };
 
-    pub static ref DATA: HashMap<u8,Def> = {
+    pub(crate) static ref DATA: HashMap<u8,Def> = {
         let mut m = HashMap::new();
-        m.insert(0x01, Def{kind: Kind::UUID,            tp: Type::Raw   });
-        m.insert(0x02, Def{kind: Kind::Group,           tp: Type::Text  });
-        m.insert(0x03, Def{kind: Kind::Title,           tp: Type::Text  });
-        m.insert(0x04, Def{kind: Kind::Username,        tp: Type::Text  });
-        m.insert(0x05, Def{kind: Kind::Notes,           tp: Type::Text  });
-        m.insert(0x06, Def{kind: Kind::Password,        tp: Type::Text  });
-        m.insert(0x07, Def{kind: Kind::CreateTime,      tp: Type::Int   });
-        m.insert(0x09, Def{kind: Kind::AccessTime,      tp: Type::Int   });
-        m.insert(0x0a, Def{kind: Kind::ExpiryTime,      tp: Type::Int   });
-        m.insert(0x0c, Def{kind: Kind::ModifyTime,      tp: Type::Int   });
-        m.insert(0x0e, Def{kind: Kind::Autotype,        tp: Type::Text  });
-        m.insert(0x0f, Def{kind: Kind::PasswordHistory, tp: Type::Text  });
-        m.insert(0x10, Def{kind: Kind::PasswordPolicy,  tp: Type::Text  });
-        m.insert(0x12, Def{kind: Kind::RunCommand,      tp: Type::Text  });
-        m.insert(0x13, Def{kind: Kind::DClickAction,    tp: Type::Short });
-        m.insert(0x14, Def{kind: Kind::Email,           tp: Type::Text  });
-        m.insert(0x15, Def{kind: Kind::Protected,       tp: Type::Byte  });
-        m.insert(0x16, Def{kind: Kind::PasswordSymbols, tp: Type::Text  });
-        m.insert(0x17, Def{kind: Kind::SClickAction,    tp: Type::Short });
-        m.insert(0xff, Def{kind: Kind::End,             tp: Type::Raw   });
+        m.insert(0x01, Def{kind: Kind::UUID,                   tp: Type::Raw   });
+        m.insert(0x02, Def{kind: Kind::Group,                  tp: Type::Text  });
+        m.insert(0x03, Def{kind: Kind::Title,                  tp: Type::Text  });
+        m.insert(0x04, Def{kind: Kind::Username,               tp: Type::Text  });
+        m.insert(0x05, Def{kind: Kind::Notes,                  tp: Type::Text  });
+        m.insert(0x06, Def{kind: Kind::Password,               tp: Type::Text  });
+        m.insert(0x07, Def{kind: Kind::CreateTime,             tp: Type::Time  });
+        m.insert(0x08, Def{kind: Kind::PasswordModifyTime,     tp: Type::Time  });
+        m.insert(0x09, Def{kind: Kind::AccessTime,             tp: Type::Time  });
+        m.insert(0x0a, Def{kind: Kind::ExpiryTime,             tp: Type::Time  });
+        m.insert(0x0c, Def{kind: Kind::ModifyTime,             tp: Type::Time  });
+        m.insert(0x0d, Def{kind: Kind::URL,                    tp: Type::Text  });
+        m.insert(0x0e, Def{kind: Kind::Autotype,               tp: Type::Text  });
+        m.insert(0x0f, Def{kind: Kind::PasswordHistory,        tp: Type::Text  });
+        m.insert(0x10, Def{kind: Kind::PasswordPolicy,         tp: Type::Text  });
+        m.insert(0x11, Def{kind: Kind::PasswordExpiryInterval, tp: Type::Int   });
+        m.insert(0x12, Def{kind: Kind::RunCommand,             tp: Type::Text  });
+        m.insert(0x13, Def{kind: Kind::DClickAction,           tp: Type::Short });
+        m.insert(0x14, Def{kind: Kind::Email,                  tp: Type::Text  });
+        m.insert(0x15, Def{kind: Kind::Protected,              tp: Type::Byte  });
+        m.insert(0x16, Def{kind: Kind::PasswordSymbols,        tp: Type::Text  });
+        m.insert(0x17, Def{kind: Kind::SClickAction,           tp: Type::Short });
+        m.insert(0x18, Def{kind: Kind::PasswordPolicyName,     tp: Type::Text  });
+        m.insert(0x19, Def{kind: Kind::KeyboardShortcut,       tp: Type::Int   });
+        m.insert(0x1a, Def{kind: Kind::AttachmentRef,          tp: Type::Raw   });
+        m.insert(0x1b, Def{kind: Kind::TwoFactorKey,           tp: Type::Raw   });
+        m.insert(0x1c, Def{kind: Kind::CreditCardNumber,       tp: Type::Text  });
+        m.insert(0x1d, Def{kind: Kind::CreditCardExpiration,   tp: Type::Text  });
+        m.insert(0x1e, Def{kind: Kind::CreditCardVerifValue,   tp: Type::Text  });
+        m.insert(0x1f, Def{kind: Kind::CreditCardPin,          tp: Type::Text  });
+        m.insert(0x20, Def{kind: Kind::QRCode,                 tp: Type::Text  });
+        m.insert(0x21, Def{kind: Kind::TotpConfig,             tp: Type::Byte  });
+        m.insert(0x22, Def{kind: Kind::TotpLength,             tp: Type::Byte  });
+        m.insert(0x23, Def{kind: Kind::TotpTimeStep,           tp: Type::Byte  });
+        m.insert(0x24, Def{kind: Kind::TotpStartTime,          tp: Type::Time  });
+        m.insert(0xff, Def{kind: Kind::End,                    tp: Type::Raw   });
         m
     };
 
-    pub static ref FIELD_TYPE: HashMap<Kind,u8> = {
+    pub(crate) static ref FIELD_TYPE: HashMap<Kind,u8> = {
         let mut m = HashMap::new();
         for (&tp, def) in HEADER.iter() {
             m.insert(def.kind, tp);
@@ -94,7 +187,7 @@ lazy_static! {
         m
     };
 
-    pub static ref FIELD_END: Field = Field {
+    pub(crate) static ref FIELD_END: Field = Field {
         def: Def {
             kind: Kind::End,
             tp: Type::Raw,
@@ -103,140 +196,639 @@ lazy_static! {
     };
 }
 
+/// Decoded contents of a field.
 #[derive(PartialEq,Eq,Hash,Debug,Clone)]
+#[non_exhaustive]
 pub enum Data {
     Raw(Vec<u8>),
     Byte(u8),
     Short(u16),
     Int(u32),
     Text(String),
+    Time(Time),
+    /// Contents of a sensitive field (see `Kind::is_sensitive`), only
+    /// accessible with `Field::reveal` or `Item::reveal`.
+    Sealed(secret::Sealed),
+}
+
+impl Data {
+    // Field contents as they are stored in the database.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut vc = Vec::new();
+
+        match *self {
+            Data::Raw(ref v) => vc.write_all(&v[..]).expect("Can't serialize raw bytes"),
+            Data::Byte(v) => vc.write_u8(v).expect("Can't serialize byte"),
+            Data::Short(v) => vc.write_u16::<LittleEndian>(v).expect("Can't serialize short"),
+            Data::Int(v) => vc.write_u32::<LittleEndian>(v).expect("Can't serialize int"),
+            Data::Text(ref v) => vc.write_all(&v[..].as_bytes()).expect("Can't serialize text"),
+            Data::Time(ref v) => v.serialize(&mut vc),
+            Data::Sealed(ref v) => v.open(|b| vc.extend_from_slice(b)),
+        }
+
+        return vc;
+    }
+}
+
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Data::Raw(_) => write!(f, "<raw bytes>"),
+            Data::Byte(v) => write!(f, "{}", v),
+            Data::Short(v) => write!(f, "{}", v),
+            Data::Int(v) => write!(f, "{}", v),
+            Data::Text(ref v) => write!(f, "{}", v),
+            Data::Time(ref v) => write!(f, "{}", v),
+            Data::Sealed(_) => write!(f, "<hidden>"),
+        }
+    }
+}
+
+// Fields hold passwords, notes and so on, don't leave them behind in
+// freed memory.
+impl Drop for Data {
+    fn drop(&mut self) {
+        match *self {
+            Data::Raw(ref mut v) => secret::wipe(v),
+            Data::Text(ref mut v) => unsafe { secret::wipe(v.as_bytes_mut()) },
+            _ => (),
+        }
+    }
 }
 
+/// Single record of an entry or of the database header.
 #[derive(Debug)]
 pub struct Field {
-    pub def: Def,
-    pub data: Data,
+    pub(crate) def: Def,
+    pub(crate) data: Data,
 }
 
 impl Field {
-    pub fn serialize(&self, c: &mut Cursor<Vec<u8>>, mac: &mut crypto::HMAC) {
-        let mut vc = Cursor::new(Vec::new());
+    // Sensitive fields are sealed right away, so their plaintext never
+    // stays around.
+    fn new(def: Def, data: Data) -> Field {
+        if !def.kind.is_sensitive() {
+            return Field{def, data};
+        }
 
+        return match data {
+            Data::Sealed(_) => Field{def, data},
+            _ => {
+                let mut b = data.to_bytes();
+                let sealed = secret::Sealed::new(&b);
+                secret::wipe(&mut b);
+                Field{def, data: Data::Sealed(sealed)}
+            },
+        };
+    }
+
+    pub fn kind(&self) -> Kind { self.def.kind }
+
+    /// Field contents, `Data::Sealed` for sensitive fields.
+    pub fn data(&self) -> &Data { &self.data }
+
+    /// Calls `f` with the decrypted contents of the field, which are
+    /// wiped as soon as `f` returns.
+    pub fn reveal<T, F: FnOnce(&Data) -> T>(&self, f: F) -> T {
         match self.data {
-            // TODO: serialize raw
-            Data::Raw(ref _v) => (),
-            Data::Byte(v) => vc.write_u8(v).expect("Can't serialize byte"),
-            Data::Short(v) => vc.write_u16::<LittleEndian>(v).expect("Can't serialize short"),
-            Data::Int(v) => vc.write_u32::<LittleEndian>(v).expect("Can't serialize int"),
-            Data::Text(ref v) => vc.write_all(&v[..].as_bytes()).expect("Can't serialize text"),
+            Data::Sealed(ref v) => {
+                let data = v.open(|b| decode(self.def.tp, b).unwrap_or_else(|| Data::Raw(b.to_vec())));
+                return f(&data);
+            },
+            ref data => return f(data),
         }
+    }
+
+    pub(crate) fn serialize(&self, c: &mut Cursor<Vec<u8>>, mac: &mut crypto::HMAC) {
+        let mut vc = Cursor::new(self.data.to_bytes());
+        vc.set_position(vc.get_ref().len() as u64);
 
         mac.update(&vc.get_ref()[..]);
 
         let len = vc.position();
         add_padding(&mut vc, len);
 
-        let tp = FIELD_TYPE[&self.def.kind];
+        let tp = match self.def.kind {
+            Kind::Unknown(tp) => tp,
+            kind => FIELD_TYPE[&kind],
+        };
 
         c.write_u32::<LittleEndian>(len as u32).expect("Can't serialize field length");
         c.write_u8(tp).expect("Can't serialize field type");
         c.write_all(&vc.get_ref()[..]).expect("Can't serialize field data");
+        secret::wipe(vc.get_mut());
     }
 }
 
 impl ToString for Field {
     fn to_string(&self) -> String {
-        return match self.data {
-            Data::Raw(_) => "<raw bytes>".to_string(),
-            Data::Byte(v) => v.to_string(),
-            Data::Short(v) => v.to_string(),
-            Data::Int(v) => v.to_string(),
-            Data::Text(ref v) => v.clone(),
+        return self.data.to_string();
+    }
+}
+
+/// Identifier of an entry or of the whole database.
+#[derive(PartialEq,Eq,Hash,Debug,Clone,Copy)]
+pub struct Uuid(pub [u8; 16]);
+
+impl Uuid {
+    /// Random (version 4) UUID as described in RFC 4122.
+    pub fn new_v4() -> Uuid {
+        let mut b: [u8; 16] = [0; 16];
+        rand::thread_rng().fill_bytes(&mut b);
+        b[6] = (b[6] & 0x0f) | 0x40;
+        b[8] = (b[8] & 0x3f) | 0x80;
+        return Uuid(b);
+    }
+
+    pub fn from_slice(b: &[u8]) -> Option<Uuid> {
+        if b.len() != 16 {
+            return None;
+        }
+        let mut u: [u8; 16] = [0; 16];
+        u.copy_from_slice(b);
+        return Some(Uuid(u));
+    }
+
+    /// Parses 32 hex digits, with or without dashes.
+    pub fn parse(s: &str) -> Option<Uuid> {
+        let digits: Vec<u32> = s.chars().filter(|&c| c != '-').map(|c| c.to_digit(16)).collect::<Option<_>>()?;
+        if digits.len() != 32 {
+            return None;
+        }
+
+        let mut u: [u8; 16] = [0; 16];
+        for i in 0..16 {
+            u[i] = (digits[2 * i] << 4 | digits[2 * i + 1]) as u8;
+        }
+        return Some(Uuid(u));
+    }
+
+    /// 32 hex digits without dashes, as in alias and shortcut references.
+    pub fn to_hex(&self) -> String {
+        return self.0.iter().map(|b| format!("{:02x}", b)).collect();
+    }
+}
+
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, b) in self.0.iter().enumerate() {
+            if i == 4 || i == 6 || i == 8 || i == 10 {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", b)?;
+        }
+        return Ok(());
+    }
+}
+
+// How a time was stored, so it can be written back unchanged.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum TimeFormat {
+    Int32,
+    Int40,
+    Int64,
+    Hex,
+    HexUpper,
+}
+
+/// Point in time as seconds since the Unix epoch.
+///
+/// Password Safe stores times as 32, 40 or 64-bit little-endian integers
+/// or (in old headers) as 8 hex characters, times are compared by value
+/// regardless of the encoding.
+#[derive(Debug,Clone,Copy)]
+pub struct Time {
+    secs: u64,
+    format: TimeFormat,
+}
+
+impl Time {
+    pub fn from_secs(secs: u64) -> Time {
+        let format = if secs >> 32 == 0 {
+            TimeFormat::Int32
+        } else if secs >> 40 == 0 {
+            TimeFormat::Int40
+        } else {
+            TimeFormat::Int64
+        };
+        return Time{secs, format};
+    }
+
+    pub fn now() -> Time {
+        return match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => Time::from_secs(d.as_secs()),
+            Err(_) => Time::from_secs(0),
+        };
+    }
+
+    pub fn secs(&self) -> u64 { self.secs }
+
+    fn parse(data: &[u8]) -> Option<Time> {
+        if data.len() == 8 && data.iter().all(|b| (*b as char).is_ascii_hexdigit()) {
+            let s = String::from_utf8_lossy(data);
+            let format = if data.iter().any(|b| (*b as char).is_ascii_uppercase()) {
+                TimeFormat::HexUpper
+            } else {
+                TimeFormat::Hex
+            };
+            return u64::from_str_radix(&s, 16).ok().map(|secs| Time{secs, format});
+        }
+
+        let format = match data.len() {
+            4 => TimeFormat::Int32,
+            5 => TimeFormat::Int40,
+            8 => TimeFormat::Int64,
+            _ => return None,
+        };
+
+        let mut secs: u64 = 0;
+        for (i, b) in data.iter().enumerate() {
+            secs |= (*b as u64) << (8 * i);
+        }
+        return Some(Time{secs, format});
+    }
+
+    fn serialize(&self, vc: &mut Vec<u8>) {
+        let len = match self.format {
+            TimeFormat::Hex => return vc.extend_from_slice(format!("{:08x}", self.secs).as_bytes()),
+            TimeFormat::HexUpper => return vc.extend_from_slice(format!("{:08X}", self.secs).as_bytes()),
+            TimeFormat::Int32 => 4,
+            TimeFormat::Int40 => 5,
+            TimeFormat::Int64 => 8,
+        };
+
+        for i in 0..len {
+            vc.push((self.secs >> (8 * i)) as u8);
         }
     }
+
+    // year, month, day, hours, minutes, seconds of a time shifted by
+    // offset, later times are shown as the end of year 9999
+    fn civil(&self, offset: i64) -> (i64, u32, u32, u32, u32, u32) {
+        let t = self.secs.min(MAX_SHOWN) as i64 + offset;
+        let days = if t >= 0 { t / 86400 } else { (t - 86399) / 86400 };
+        let rem = t - days * 86400;
+
+        // days since 1970-01-01 to a proleptic Gregorian date
+        let z = days + 719468;
+        let era = if z >= 0 { z } else { z - 146096 } / 146097;
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let d = doy - (153 * mp + 2) / 5 + 1;
+        let m = if mp < 10 { mp + 3 } else { mp - 9 };
+        let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+
+        return (y, m as u32, d as u32, (rem / 3600) as u32, (rem / 60 % 60) as u32, (rem % 60) as u32);
+    }
+
+    /// "2012-06-08T15:16:58Z"
+    pub fn to_iso8601(&self) -> String {
+        let (y, m, d, hh, mm, ss) = self.civil(0);
+        return format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", y, m, d, hh, mm, ss);
+    }
+
+    /// "2012-06-08 15:16:58 UTC"
+    pub fn to_utc_string(&self) -> String {
+        let (y, m, d, hh, mm, ss) = self.civil(0);
+        return format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", y, m, d, hh, mm, ss);
+    }
+
+    /// "2012-06-08 17:16:58 +0200" in the local time zone.
+    pub fn to_local_string(&self) -> String {
+        let offset = local_offset(self.secs.min(MAX_SHOWN));
+        let (y, m, d, hh, mm, ss) = self.civil(offset);
+        let sign = if offset < 0 { '-' } else { '+' };
+        let offset = offset.abs() / 60;
+        return format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} {}{:02}{:02}", y, m, d, hh, mm, ss, sign, offset / 60, offset % 60);
+    }
+}
+
+// 9999-12-31 23:59:59 UTC, the last time with a four digit year
+const MAX_SHOWN: u64 = 253402300799;
+
+// UTC offset of the local time zone in seconds at the given time.
+#[cfg(unix)]
+fn local_offset(secs: u64) -> i64 {
+    let t = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { ::std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&t, &mut tm) }.is_null() {
+        return 0;
+    }
+    return tm.tm_gmtoff as i64;
+}
+
+#[cfg(not(unix))]
+fn local_offset(_secs: u64) -> i64 {
+    0
+}
+
+impl PartialEq for Time {
+    fn eq(&self, other: &Time) -> bool { self.secs == other.secs }
+}
+
+impl Eq for Time {}
+
+impl PartialOrd for Time {
+    fn partial_cmp(&self, other: &Time) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+impl Ord for Time {
+    fn cmp(&self, other: &Time) -> Ordering { self.secs.cmp(&other.secs) }
+}
+
+impl Hash for Time {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.secs.hash(state);
+    }
 }
 
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_iso8601())
+    }
+}
+
+/// Database entry (or header) as an ordered list of fields.
+///
+/// Fields are kept in the order they were read, some header fields
+/// (e.g. EmptyGroups) may legitimately appear more than once.
 #[derive(Debug)]
 pub struct Item {
-    pub field: HashMap<Kind, Field>,
+    pub(crate) field: Vec<Field>,
 }
 
 impl Item {
-    pub fn iter(&self) -> std::collections::hash_map::Iter<Kind, Field> { self.field.iter() }
+    pub fn new() -> Item {
+        return Item{field: Vec::new()};
+    }
+
+    /// Iterates over all fields in their on-disk order.
+    pub fn iter(&self) -> std::slice::Iter<Field> { self.field.iter() }
 
+    /// Returns the first field of the given kind.
     pub fn get(&self, k: Kind) -> Option<&Data> {
-        match self.field.get(&k) {
+        match self.field.iter().find(|f| f.def.kind == k) {
             None => return None,
             Some(v) => return Some(&v.data),
         }
     }
 
-    pub fn insert(&mut self, kind: Kind, data: &Data) {
-        for (_, def) in DATA.iter() {
-            if def.kind == kind {
-                let data = data.clone();
-                let def = def.clone();
-                self.field.insert(kind, Field{def, data});
-                return;
+    /// Entry name as shown by Password Safe: "Group.Title".
+    pub fn name(&self) -> String {
+        let mut name = String::new();
+
+        match self.get(Kind::Group) {
+            Some(&Data::Text(ref v)) => {
+                name.push_str(v);
+                name.push('.');
+            },
+            _ => (),
+        }
+
+        match self.get(Kind::Title) {
+            Some(&Data::Text(ref v)) => name.push_str(v),
+            _ => (),
+        }
+
+        return name;
+    }
+
+    pub fn uuid(&self) -> Option<Uuid> {
+        match self.get(Kind::UUID) {
+            Some(&Data::Raw(ref v)) => return Uuid::from_slice(v),
+            _ => return None,
+        }
+    }
+
+    /// Returns the first field of the given kind as time.
+    pub fn time(&self, k: Kind) -> Option<Time> {
+        match self.get(k) {
+            Some(&Data::Time(t)) => return Some(t),
+            Some(&Data::Int(t)) => return Some(Time::from_secs(t as u64)),
+            _ => return None,
+        }
+    }
+
+    /// What the entry refers to if it's an alias or a shortcut.
+    pub fn reference(&self) -> Option<Reference> {
+        return self.reveal(Kind::Password, |d| {
+            match d {
+                Some(&Data::Text(ref v)) => Reference::parse(v),
+                _ => None,
+            }
+        });
+    }
+
+    /// Password history, None if the entry has none or it's malformed.
+    pub fn history(&self) -> Option<PasswordHistory> {
+        return self.reveal(Kind::PasswordHistory, |d| {
+            match d {
+                Some(&Data::Text(ref v)) => PasswordHistory::parse(v),
+                _ => None,
             }
+        });
+    }
+
+    /// Password policy of the entry, None if it has none or it's malformed.
+    pub fn policy(&self) -> Option<PasswordPolicy> {
+        match self.get(Kind::PasswordPolicy) {
+            Some(&Data::Text(ref v)) => return PasswordPolicy::parse(v),
+            _ => return None,
+        }
+    }
+
+    /// Generates a password that follows the policy of the entry (or the
+    /// default one) and its own symbols if it has them. Named policies are
+    /// kept in the database header, see `V3::generate_password`.
+    pub fn generate_password(&self) -> Result<secret::SecretString, Error> {
+        let policy = self.policy().unwrap_or_default();
+        match self.get(Kind::PasswordSymbols) {
+            Some(&Data::Text(ref s)) => return policy.generate(Some(s)),
+            _ => return policy.generate(None),
+        }
+    }
+
+    /// TOTP parameters of the entry, None if it has no two-factor key or
+    /// it uses an unknown algorithm.
+    pub fn totp(&self) -> Option<Totp> {
+        let key = self.reveal(Kind::TwoFactorKey, |d| {
+            match d {
+                Some(&Data::Raw(ref v)) if !v.is_empty() => Some(v.clone()),
+                _ => None,
+            }
+        });
+
+        let mut totp = Totp::new(key?);
+        match self.get(Kind::TotpConfig) {
+            Some(&Data::Byte(v)) => totp.algorithm = Totp::algorithm_from_config(v)?,
+            _ => (),
+        }
+        match self.get(Kind::TotpLength) {
+            // same range as otpauth:// URIs, others are corrupt
+            Some(&Data::Byte(v)) if v >= 1 && v <= 10 => totp.digits = v,
+            _ => (),
+        }
+        match self.get(Kind::TotpTimeStep) {
+            Some(&Data::Byte(v)) => { totp.set_period(v); },
+            _ => (),
         }
+        match self.time(Kind::TotpStartTime) {
+            Some(t) => totp.start = t.secs(),
+            None => (),
+        }
+        return Some(totp);
+    }
+
+    /// Replaces the two-factor key and TOTP parameters of the entry,
+    /// parameters with default values are left out.
+    pub fn set_totp(&mut self, t: &Totp) {
+        let default = Totp::new(Vec::new());
+        self.insert(Kind::TwoFactorKey, &Data::Raw(t.key.clone()));
+
+        let fields = [
+            (Kind::TotpConfig, t.config() != default.config(), Data::Byte(t.config())),
+            (Kind::TotpLength, t.digits != default.digits, Data::Byte(t.digits)),
+            (Kind::TotpTimeStep, t.period() != default.period(), Data::Byte(t.period())),
+            (Kind::TotpStartTime, t.start != default.start, Data::Time(Time::from_secs(t.start))),
+        ];
+        for &(kind, set, ref data) in fields.iter() {
+            if set {
+                self.insert(kind, data);
+            } else {
+                self.remove(kind);
+            }
+        }
+    }
+
+    /// Replaces the password history of the entry.
+    pub fn set_history(&mut self, h: &PasswordHistory) {
+        self.insert(Kind::PasswordHistory, &h.to_data());
+    }
+
+    /// Calls `f` with the decrypted contents of the first field of the
+    /// given kind, see `Field::reveal`.
+    pub fn reveal<T, F: FnOnce(Option<&Data>) -> T>(&self, k: Kind, f: F) -> T {
+        match self.field.iter().find(|f| f.def.kind == k) {
+            None => return f(None),
+            Some(v) => return v.reveal(|d| f(Some(d))),
+        }
+    }
+
+    /// Returns all fields of the given kind.
+    pub fn get_all(&self, k: Kind) -> Vec<&Data> {
+        return self.field.iter().filter(|f| f.def.kind == k).map(|f| &f.data).collect();
     }
 
-    pub fn serialize(&self, c: &mut Cursor<Vec<u8>>, mac: &mut crypto::HMAC) {
-        for (_, field) in &self.field {
+    /// Replaces the first field of the given kind or appends a new one.
+    /// Kinds that can't be stored in an entry or header are ignored.
+    pub fn insert(&mut self, kind: Kind, data: &Data) {
+        let def = match find_def(kind) {
+            Some(def) => def,
+            None => return,
+        };
+
+        let field = Field::new(def, data.clone());
+        match self.field.iter_mut().find(|f| f.def.kind == kind) {
+            Some(f) => *f = field,
+            None => self.field.push(field),
+        }
+    }
+
+    /// Appends a field even if there is already one of the same kind.
+    pub fn push(&mut self, kind: Kind, data: &Data) {
+        match find_def(kind) {
+            Some(def) => self.field.push(Field::new(def, data.clone())),
+            None => (),
+        }
+    }
+
+    /// Removes all fields of the given kind.
+    pub fn remove(&mut self, kind: Kind) {
+        self.field.retain(|f| f.def.kind != kind);
+    }
+
+    pub(crate) fn serialize(&self, c: &mut Cursor<Vec<u8>>, mac: &mut crypto::HMAC) {
+        for field in &self.field {
             field.serialize(c, mac);
         }
     }
 }
 
 pub fn new() -> Item {
-    let m = HashMap::new();
-    return Item{field: m};
+    return Item::new();
 }
 
-fn new_field(map: &HashMap<u8,Def>, val: u8, data: &[u8]) -> Field {
-    match map.get(&val) {
-        None => return Field{
-            def: Def {
-                kind: Kind::Unknown,
-                tp: Type::Raw,
-            },
-            data: Data::Raw(data.to_vec()),
-        },
-        Some(def) => {
-            match def.tp {
-                Type::Byte =>
-                    return Field{
-                        def: def.clone(),
-                        data: Data::Byte(data[0]),
-                    },
-
-                Type::Short =>
-                    return Field{
-                        def: def.clone(),
-                        data: Data::Short(((data[1] as u16) << 8) | (data[0] as u16)),
-                    },
-
-                Type::Int =>
-                    return Field{
-                        def: def.clone(),
-                        data: Data::Int(((data[3] as u32) << 24) | ((data[2] as u32) << 16) | ((data[1] as u32) << 8) | (data[0] as u32)),
-                    },
-
-                Type::Text =>
-                    return Field{
-                        def: def.clone(),
-                        data: Data::Text(String::from_utf8_lossy(data).into_owned()),
-                    },
-
-                Type::Raw =>
-                    return Field{
-                        def: def.clone(),
-                        data: Data::Raw(data.to_vec()),
-                    },
+fn find_def(kind: Kind) -> Option<Def> {
+    match kind {
+        Kind::Unknown(_) => return Some(Def{kind, tp: Type::Raw}),
+        _ => (),
+    }
+
+    for map in [&*DATA, &*HEADER].iter() {
+        for (_, def) in map.iter() {
+            if def.kind == kind {
+                return Some(def.clone());
             }
         }
     }
+    return None;
+}
+
+fn unknown_field(val: u8, data: &[u8]) -> Field {
+    return Field{
+        def: Def {
+            kind: Kind::Unknown(val),
+            tp: Type::Raw,
+        },
+        data: Data::Raw(data.to_vec()),
+    };
+}
+
+// Anything we can't decode into exactly the same bytes on serialization
+// (unknown type, unexpected length, invalid UTF-8) is kept as raw bytes
+// together with the original type byte, so it's written back unchanged.
+fn new_field(map: &HashMap<u8,Def>, val: u8, data: &[u8]) -> Field {
+    let def = match map.get(&val) {
+        None => return unknown_field(val, data),
+        Some(def) => def.clone(),
+    };
+
+    return match decode(def.tp, data) {
+        Some(data) => Field::new(def, data),
+        None => unknown_field(val, data),
+    };
+}
+
+fn decode(tp: Type, data: &[u8]) -> Option<Data> {
+    return match tp {
+        Type::Byte if data.len() == 1 =>
+            Some(Data::Byte(data[0])),
+
+        Type::Short if data.len() == 2 =>
+            Some(Data::Short(((data[1] as u16) << 8) | (data[0] as u16))),
+
+        Type::Int if data.len() == 4 =>
+            Some(Data::Int(((data[3] as u32) << 24) | ((data[2] as u32) << 16) | ((data[1] as u32) << 8) | (data[0] as u32))),
+
+        Type::Text =>
+            match String::from_utf8(data.to_vec()) {
+                Ok(s) => Some(Data::Text(s)),
+                Err(e) => {
+                    secret::wipe(&mut e.into_bytes());
+                    None
+                },
+            },
+
+        Type::Time =>
+            Time::parse(data).map(Data::Time),
+
+        Type::Raw =>
+            Some(Data::Raw(data.to_vec())),
+
+        _ => None,
+    };
 }
 
 fn add_padding(c: &mut Cursor<Vec<u8>>, len: u64) {
@@ -249,49 +841,72 @@ fn add_padding(c: &mut Cursor<Vec<u8>>, len: u64) {
 }
 
 fn skip_padding(c: &mut Cursor<&[u8]>, len: u32) {
-    let rem = (5 + len) % 16;
+    let rem = (5 + len as u64) % 16;
     if rem != 0 {
-        c.seek(SeekFrom::Current(16 - rem as i64)).expect("Corrupted field, can't skip padding");
+        let pos = c.position() + 16 - rem;
+        c.set_position(pos);
     }
 }
 
-pub fn parse_field(mac: &mut crypto::HMAC, map: &HashMap<u8,Def>, c: &mut Cursor<&[u8]>) -> Option<Field> {
+// Returns None when there is no more data, a field that doesn't fit
+// into the remaining data is reported as corrupted.
+pub(crate) fn parse_field(mac: &mut crypto::HMAC, map: &HashMap<u8,Def>, c: &mut Cursor<&[u8]>) -> Result<Option<Field>, Error> {
+    let offset = c.position();
+    let remaining = (c.get_ref().len() as u64).saturating_sub(offset);
+
+    if remaining == 0 {
+        return Ok(None);
+    }
+
     let len = match c.read_u32::<LittleEndian>() {
         Ok(v) => v,
-        Err(_) => return None,
+        Err(_) => return Err(Error::CorruptField{offset}),
     };
 
-    let tp = c.read_u8().expect("Corrupted field, can't read type");
+    let tp = match c.read_u8() {
+        Ok(v) => v,
+        Err(_) => return Err(Error::CorruptField{offset}),
+    };
+
+    if len as u64 > remaining - 5 {
+        return Err(Error::CorruptField{offset});
+    }
 
     let mut v = Vec::new();
     v.resize(len as usize, 0);
-    c.read_exact(&mut v).expect("Corrupted field, can't read contents");
+    match c.read_exact(&mut v) {
+        Ok(_) => (),
+        Err(_) => return Err(Error::CorruptField{offset}),
+    }
 
     mac.update(&v[..]);
 
-    let i = Some(new_field(map, tp, &v[..]));
+    let i = new_field(map, tp, &v[..]);
+    secret::wipe(&mut v);
     skip_padding(c, len);
-    return i;
+    return Ok(Some(i));
 }
 
-pub fn parse(mac: &mut crypto::HMAC, map: &HashMap<u8, Def>, c: &mut Cursor<&[u8]>) -> Option<Item> {
-    let mut m = HashMap::new();
+// Returns None when there are no more items, data that ends in the
+// middle of an item (without End field) is reported as truncated.
+pub(crate) fn parse(mac: &mut crypto::HMAC, map: &HashMap<u8, Def>, c: &mut Cursor<&[u8]>) -> Result<Option<Item>, Error> {
+    let mut m = Vec::new();
 
     loop {
-        match parse_field(mac, map, c) {
+        match parse_field(mac, map, c)? {
             Some(f) => {
                 if f.def.kind == Kind::End {
                     break;
                 }
-                if f.def.kind != Kind::Unknown {
-                    m.insert(f.def.kind, f);
-                }
+                m.push(f);
             },
             None => {
-                assert!(m.len() == 0);
-                return None;
+                if m.len() != 0 {
+                    return Err(Error::Truncated);
+                }
+                return Ok(None);
             },
         }
     }
-    return Some(Item{field: m});
+    return Ok(Some(Item{field: m}));
 }
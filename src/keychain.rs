@@ -1,192 +1,378 @@
 use std;
-use std::str;
-use std::error::Error;
-use std::fs::File;
-use std::io::Cursor;
+use std::cmp;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+use std::io;
+use std::io::{BufWriter, Cursor};
 use std::io::Read;
 use std::io::Write;
+use std::env;
+use std::fmt;
+use std::time::Instant;
 use byteorder::{ReadBytesExt, LittleEndian, WriteBytesExt};
 use regex::Regex;
 use rand::{OsRng, Rng};
 
 use crypto;
+use error::Error;
+use history::PasswordHistory;
 use item;
+use lock;
+use policy;
+use reference;
+use secret;
+use secret::{SecretKey, SecretString};
+
+// Format version we write, see formatV3.txt
+const VERSION: u16 = 0x030d;
+
+/// Minimum number of password stretching iterations allowed by the V3
+/// format.
+pub const MIN_ITER: u32 = 2048;
+
+// used for new databases unless set_iterations is called
+const DEFAULT_ITER: u32 = 100000;
+
+/// What `save_with` does when the database was changed on disk since it
+/// was opened (or last saved).
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Conflict {
+    /// fail with `Error::Modified`
+    Abort,
+    /// replace whatever is on disk
+    Overwrite,
+    /// merge entries from the disk (see `merge`) and save the result
+    Merge,
+}
+
+/// Source of the current time for create/modify/access times, see
+/// `V3::set_clock`.
+pub trait Clock: fmt::Debug {
+    fn now(&self) -> item::Time;
+}
+
+/// Wall clock time.
+#[derive(Debug)]
+pub struct SystemClock;
 
+impl Clock for SystemClock {
+    fn now(&self) -> item::Time { item::Time::now() }
+}
+
+/// Password Safe V3 database.
 #[derive(Debug)]
 pub struct V3 {
-    path: String,
+    path: Option<String>,
+    backups: usize,
+    lock: Option<lock::Lock>,
+    locked_by: Option<lock::Owner>,
+    // hash of the file as we've last seen it, None if it didn't exist
+    disk_hash: Option<[u8; 32]>,
+    // entries that were in the file as we've last seen it
+    disk_uuids: Vec<item::Uuid>,
+    // entries that may have been changed since then
+    changed: Vec<item::Uuid>,
     salt: [u8; 32],
     iter: u32,
-    header: Option<item::Item>,
+    header: item::Item,
     items: Vec<item::Item>,
+    clock: Box<Clock>,
+    update_times: bool,
+    // no core dumps while decrypted entries are around
+    _guard: secret::Guard,
 }
 
 impl V3 {
-    pub fn open(path: &str, password: &str) -> Option<V3> {
+    /// Opens and decrypts an existing database.
+    pub fn open(path: &str, password: &str) -> Result<V3, Error> {
+        let d = fs::read(path)?;
+        let mut kc = V3::from_reader(&d[..], password)?;
+        kc.path = Some(path.to_string());
+        kc.disk_hash = Some(crypto::sha256(&d));
+        return Ok(kc);
+    }
+
+    /// Opens a database for writing, taking Password Safe compatible lock.
+    ///
+    /// When the database is locked by somebody else it's opened read-only
+    /// (see `read_only` and `locked_by`), a lock left behind by a dead
+    /// process on this machine is reported as `Error::StaleLock`.
+    pub fn open_locked(path: &str, password: &str) -> Result<V3, Error> {
+        let (lock, locked_by) = match lock::Lock::acquire(path) {
+            Ok(l) => (Some(l), None),
+            Err(Error::Locked(owner)) => (None, Some(owner)),
+            Err(e) => return Err(e),
+        };
+
+        let mut kc = V3::open(path, password)?;
+        kc.lock = lock;
+        kc.locked_by = locked_by;
+        return Ok(kc);
+    }
+
+    /// Whether somebody else held the lock when the database was opened.
+    pub fn read_only(&self) -> bool { self.locked_by.is_some() }
+
+    pub fn locked_by(&self) -> Option<&lock::Owner> { self.locked_by.as_ref() }
+
+    /// Decrypts a database from an arbitrary stream (memory, pipe and so
+    /// on), such database can only be saved with `write_to`.
+    pub fn from_reader<R: Read>(mut r: R, password: &str) -> Result<V3, Error> {
         crypto::init();
 
         let mut kc = V3 {
-            path: path.to_string(),
+            path: None,
+            backups: 0,
+            lock: None,
+            locked_by: None,
+            disk_hash: None,
+            disk_uuids: Vec::new(),
+            changed: Vec::new(),
             salt: [0; 32],
             iter: 0,
-            header: None,
+            header: item::new(),
             items: Vec::new(),
+            clock: Box::new(SystemClock),
+            update_times: true,
+            _guard: secret::Guard::new(),
         };
 
-        match kc.unlock(password) {
-            true => return Some(kc),
-            false => return None,
-        };
+        kc.unlock(&mut r, password)?;
+        kc.disk_uuids = kc.uuids();
+        return Ok(kc);
     }
 
-    fn has_tag(&self, f: &mut File) -> bool {
+    fn has_tag(&self, f: &mut Read) -> Result<(), Error> {
         let mut tag: [u8; 4] = [0; 4];
+        f.read_exact(&mut tag)?;
 
-        match f.read_exact(&mut tag) {
-            Err(e) => {
-                eprintln!("Can't read tag: {}", e.description());
-                return false;
-            }
-            _ => (),
-        };
-
-        match str::from_utf8(&tag) {
-            Err(e) => {
-                eprintln!("Can't convert tag to UTF8: {}", e.description());
-                return false;
-            }
-            Ok(tag) => {
-                if tag != "PWS3" {
-                    eprintln!("Got invalid tag: {}", tag);
-                    return false;
-                }
-                return true;
-            }
+        if &tag != b"PWS3" {
+            return Err(Error::BadTag);
         }
+        return Ok(());
     }
 
-    fn stretch_password(&self, f: &mut File, password: &str) -> Option<[u8; 32]> {
+    fn stretch_password(&self, f: &mut Read, password: &str) -> Result<SecretKey, Error> {
         let mut expected: [u8; 32] = [0; 32];
-        match f.read_exact(&mut expected) {
-            Ok(_) => {
-                let got = crypto::stretch(password, &self.salt, self.iter);
-                if crypto::sha256(&got) != expected {
-                    return None;
-                }
-                return Some(got);
-            }
-            _ => return None,
+        f.read_exact(&mut expected)?;
+
+        let got = crypto::stretch(password, &self.salt, self.iter);
+        if !crypto::ct_eq(&crypto::sha256(&got[..]), &expected) {
+            return Err(Error::WrongPassword);
         }
+        return Ok(got);
     }
 
-    pub fn save(&mut self, password: &str) -> bool {
-        let mut rng = match OsRng::new() {
-            Ok(g) => g,
-            Err(e) => {
-                eprintln!("Can't obtain secure RNG: {}", e.description());
-                return false;
-            }
-        };
+    /// Encrypts the database with the given password and writes it back
+    /// to the path it was opened from (or created with).
+    ///
+    /// The database is written into a temporary file next to the original
+    /// one, which is synced and decrypted back before it's renamed over
+    /// the original, so the existing database is never left half-written.
+    ///
+    /// Fails with `Error::Modified` if somebody else changed the database
+    /// since it was opened, see `save_with`.
+    pub fn save(&mut self, password: &str) -> Result<(), Error> {
+        return self.save_with(password, Conflict::Abort);
+    }
 
-        let mut f = match File::create(&self.path) {
-            Ok(f) => f,
-            Err(e) => {
-                eprintln!("Can't open database at '{}': {}", self.path, e.description());
-                return false;
-            }
+    /// Same as `save`, but resolves changes made on disk since the database
+    /// was opened according to `on_conflict`.
+    pub fn save_with(&mut self, password: &str, on_conflict: Conflict) -> Result<(), Error> {
+        if self.read_only() {
+            return Err(Error::ReadOnly);
+        }
+
+        let path = match self.path {
+            Some(ref path) => PathBuf::from(path),
+            None => return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, "database has no path"))),
         };
 
-        match f.write_all(b"PWS3") {
-            Err(e) => {
-                eprintln!("Can't write tag: {}", e.description());
-                return false;
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(format!(".{}.tmp", process::id()));
+        let tmp = PathBuf::from(tmp);
+
+        if self.disk_changed(&path)? {
+            match on_conflict {
+                Conflict::Abort => return Err(Error::Modified),
+                Conflict::Overwrite => (),
+                Conflict::Merge => {
+                    let other = V3::open(&path.to_string_lossy(), password)?;
+                    self.merge(other);
+                },
             }
-            _ => (),
         }
 
-        rng.fill_bytes(&mut self.salt);
-
-        match f.write_all(&self.salt) {
+        let hash = match self.replace(&path, &tmp, password) {
+            Ok(hash) => hash,
             Err(e) => {
-                eprintln!("Can't write salt: {}", e.description());
-                return false;
+                fs::remove_file(&tmp).ok();
+                return Err(e);
             },
-            _ => (),
         };
+        sync_dir(&path);
 
-        match f.write_u32::<LittleEndian>(self.iter) {
-            Err(e) => {
-                eprintln!("Can't write number of password iterations: {}", e.description());
-                return false;
-            },
-            _ => (),
+        self.disk_hash = Some(hash);
+        self.disk_uuids = self.uuids();
+        self.changed.clear();
+
+        return Ok(());
+    }
+
+    fn disk_changed(&self, path: &Path) -> Result<bool, Error> {
+        let hash = match fs::read(path) {
+            Ok(d) => Some(crypto::sha256(&d)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(Error::from(e)),
         };
+        return Ok(hash != self.disk_hash);
+    }
 
-        // stretch password
-        let stretched = crypto::stretch(password, &self.salt, self.iter);
+    fn uuids(&self) -> Vec<item::Uuid> {
+        self.items.iter().filter_map(|i| i.uuid()).collect()
+    }
 
-        match f.write_all(&crypto::sha256(&stretched)) {
-            Err(e) => {
-                eprintln!("Can't write hash of a stretched password: {}", e.description());
-                return false;
+    /// Merges entries of another copy of the same database by UUID.
+    ///
+    /// Entries that only exist in `other` are added unless they were
+    /// deleted here, entries that exist in both are replaced when the copy
+    /// in `other` was modified later. Entries deleted in `other` are
+    /// removed here too unless they were changed since the last save.
+    /// Entries without UUID can't be matched and are ignored.
+    pub fn merge(&mut self, other: V3) {
+        let other_uuids = other.uuids();
+        let (disk_uuids, changed) = (&self.disk_uuids, &self.changed);
+        self.items.retain(|i| match i.uuid() {
+            Some(ref uuid) => !disk_uuids.contains(uuid) || other_uuids.contains(uuid) || changed.contains(uuid),
+            None => true,
+        });
+
+        for i in other.items {
+            let uuid = match i.uuid() {
+                Some(uuid) => uuid,
+                None => continue,
+            };
+
+            match self.items.iter().position(|j| j.uuid() == Some(uuid)) {
+                Some(pos) => {
+                    if modify_time(&i) > modify_time(&self.items[pos]) {
+                        self.items[pos] = i;
+                    }
+                },
+                None => {
+                    if !self.disk_uuids.contains(&uuid) {
+                        self.items.push(i);
+                    }
+                },
             }
-            _ => (),
         }
+    }
 
-        // generate and write all initial settings
-        let mut k: [u8; 32] = [0; 32];
-        rng.fill_bytes(&mut k);
-
-        let mut l: [u8; 32] = [0; 32];
-        rng.fill_bytes(&mut l);
+    // Writes the database to tmp and moves it over path, the caller has to
+    // remove tmp on errors.
+    fn replace(&mut self, path: &Path, tmp: &Path, password: &str) -> Result<[u8; 32], Error> {
+        let hash = self.write_verified(tmp, password)?;
 
-        let b12 = match crypto::encrypt_block_ecb(&k, &stretched) {
-            Ok(k) => k,
-            Err(e) => {
-                eprintln!("Can't encrypt K: {}", e.description());
-                return false;
+        match fs::metadata(path) {
+            Ok(m) => {
+                fs::set_permissions(tmp, m.permissions())?;
+                self.rotate_backups(path)?;
             },
-        };
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(Error::from(e)),
+        }
 
-        let b34 = match crypto::encrypt_block_ecb(&l, &stretched) {
-            Ok(l) => l,
-            Err(e) => {
-                eprintln!("Can't encrypt L: {}", e.description());
-                return false;
-            },
-        };
+        fs::rename(tmp, path)?;
+        return Ok(hash);
+    }
 
-        match f.write_all(&b12) {
-            Err(e) => {
-                eprintln!("Can't write K: {}", e.description());
-                return false;
-            }
-            _ => (),
+    fn write_verified(&mut self, tmp: &Path, password: &str) -> Result<[u8; 32], Error> {
+        let f = create_private(tmp)?;
+        let mut w = BufWriter::new(f);
+        self.write_to(&mut w, password)?;
+
+        match w.into_inner() {
+            Ok(f) => f.sync_all()?,
+            Err(e) => return Err(Error::Io(e.into())),
         }
 
-        match f.write_all(&b34) {
-            Err(e) => {
-                eprintln!("Can't write L: {}", e.description());
-                return false;
+        let d = fs::read(tmp)?;
+        V3::from_reader(&d[..], password)?;
+        return Ok(crypto::sha256(&d));
+    }
+
+    // Keeps up to self.backups previous versions of the database using
+    // Password Safe naming: foo.psafe3 -> foo_001.ibak (the most recent),
+    // foo_002.ibak and so on.
+    fn rotate_backups(&self, path: &Path) -> Result<(), Error> {
+        if self.backups == 0 {
+            return Ok(());
+        }
+
+        for n in (1..self.backups).rev() {
+            let from = backup_path(path, n);
+            if from.exists() {
+                fs::rename(&from, backup_path(path, n + 1))?;
             }
-            _ => (),
         }
 
+        fs::copy(path, backup_path(path, 1))?;
+        return Ok(());
+    }
+
+    /// Number of password stretching iterations (SHA-256 rounds).
+    pub fn iterations(&self) -> u32 { self.iter }
+
+    /// Sets number of password stretching iterations used by the next
+    /// save, values below `MIN_ITER` are raised to it.
+    pub fn set_iterations(&mut self, iter: u32) {
+        self.iter = cmp::max(iter, MIN_ITER);
+    }
+
+    /// Sets how many previous versions of the database `save` keeps.
+    pub fn set_backups(&mut self, n: usize) {
+        self.backups = n;
+    }
+
+    /// Encrypts the database with the given password into an arbitrary
+    /// stream.
+    pub fn write_to<W: Write>(&mut self, mut f: W, password: &str) -> Result<(), Error> {
+        let mut rng = OsRng::new()?;
+
+        f.write_all(b"PWS3")?;
+
+        rng.fill_bytes(&mut self.salt);
+        f.write_all(&self.salt)?;
+        f.write_u32::<LittleEndian>(self.iter)?;
+
+        // stretch password
+        let stretched = crypto::stretch(password, &self.salt, self.iter);
+        f.write_all(&crypto::sha256(&stretched[..]))?;
+
+        // generate and write all initial settings
+        let mut k = SecretKey::new();
+        rng.fill_bytes(&mut k[..]);
+
+        let mut l = SecretKey::new();
+        rng.fill_bytes(&mut l[..]);
+
+        let b12 = crypto::encrypt_block_ecb(&k[..], &stretched[..])?;
+        let b34 = crypto::encrypt_block_ecb(&l[..], &stretched[..])?;
+        f.write_all(&b12)?;
+        f.write_all(&b34)?;
+
         let mut iv: [u8; 16] = [0; 16];
         rng.fill_bytes(&mut iv);
+        f.write_all(&iv)?;
 
-        match f.write_all(&iv) {
-            Err(e) => {
-                eprintln!("Can't write IV: {}", e.description());
-                return false;
-            }
-            _ => (),
-        }
-
-        let mut mac = crypto::HMAC::new(&l);
+        let mut mac = crypto::HMAC::new(&l[..]);
         let mut c = Cursor::new(Vec::new());
 
-        // TODO: header
+        self.update_header();
+        self.header.serialize(&mut c, &mut mac);
         item::FIELD_END.serialize(&mut c, &mut mac);
 
         self.each(&mut |_: &str, i: &item::Item| {
@@ -196,235 +382,570 @@ impl V3 {
 
         let data = c.get_mut();
         let bytes = &mut data[..];
-        match crypto::encrypt_inplace(bytes, &k, &iv) {
-            Err(e) => {
-                eprintln!("Can't encrypt data: {}", e.description());
-                return false;
-            }
-            _ => (),
-        }
+        crypto::encrypt_inplace(bytes, &k[..], &iv)?;
+        f.write_all(bytes)?;
 
-        match f.write_all(bytes) {
-            Err(e) => {
-                eprintln!("Can't write data: {}", e.description());
-                return false;
-            }
-            _ => (),
-        }
+        f.write_all(b"PWS3-EOFPWS3-EOF")?;
+        f.write_all(&mac.get_mac())?;
+        f.flush()?;
 
-        match f.write_all(b"PWS3-EOFPWS3-EOF") {
-            Err(e) => {
-                eprintln!("Can't write EOF: {}", e.description());
-                return false;
-            }
-            _ => (),
+        return Ok(());
+    }
+
+    // Stamp the header with everything Password Safe expects to find
+    // after a save, all other header fields are written back as is.
+    fn update_header(&mut self) {
+        if self.header.get(item::Kind::Version).is_none() {
+            self.header.field.insert(0, item::Field{
+                def: item::Def{kind: item::Kind::Version, tp: item::Type::Short},
+                data: item::Data::Short(VERSION),
+            });
         }
 
-        match f.write_all(&mac.get_mac()) {
-            Err(e) => {
-                eprintln!("Can't write MAC: {}", e.description());
-                return false;
-            }
-            _ => (),
+        if self.header.uuid().is_none() {
+            self.header.insert(item::Kind::UUID, &item::Data::Raw(item::Uuid::new_v4().0.to_vec()));
         }
 
-        return true;
+        self.header.insert(item::Kind::Version, &item::Data::Short(VERSION));
+        self.header.insert(item::Kind::LastSaveTime, &item::Data::Time(self.clock.now()));
+        self.header.insert(item::Kind::LastSaveWhat, &item::Data::Text(format!("pwsf V{}", env!("CARGO_PKG_VERSION"))));
+        self.header.insert(item::Kind::LastSaveUser, &item::Data::Text(username()));
+        self.header.insert(item::Kind::LastSaveHost, &item::Data::Text(hostname()));
+        self.header.remove(item::Kind::LastSaveWho);
     }
 
-    fn unlock(&mut self, password: &str) -> bool {
-        let mut f = match File::open(&self.path) {
-            Ok(f) => f,
-            Err(e) => {
-                eprintln!("Can't open database at '{}': {}", self.path, e.description());
-                return false;
-            }
-        };
-
+    fn unlock(&mut self, f: &mut Read, password: &str) -> Result<(), Error> {
         // make sure we are reading expected file format
-        if !self.has_tag(&mut f) {
-            return false;
-        }
+        self.has_tag(f)?;
 
         // read salt and iv
-        match f.read_exact(&mut self.salt) {
-            Err(e) => {
-                eprintln!("Can't open database: {}", e.description());
-                return false;
-            },
-            _ => (),
-        }
-
-        self.iter = match f.read_u32::<LittleEndian>() {
-            Err(e) => {
-                eprintln!("Can't read number of password iterations: {}", e.description());
-                return false;
-            },
-            Ok(i) => i,
-        };
+        f.read_exact(&mut self.salt)?;
+        self.iter = f.read_u32::<LittleEndian>()?;
 
         // stretch password
-        let stretched = match self.stretch_password(&mut f, password) {
-            Some(p) => p,
-            None => {
-                eprintln!("Invalid password");
-                return false;
-            },
-        };
+        let stretched = self.stretch_password(f, password)?;
 
         // read and decrypt all initial settings
         let mut b12: [u8; 32] = [0; 32];
-        match f.read_exact(&mut b12) {
-            Err(e) => {
-                eprintln!("Can't read B12: {}", e.description());
-                return false;
-            },
-            _ => (),
-        }
+        f.read_exact(&mut b12)?;
 
         let mut b34: [u8; 32] = [0; 32];
-        match f.read_exact(&mut b34) {
-            Err(e) => {
-                eprintln!("Can't read B34: {}", e.description());
-                return false;
-            },
-            _ => (),
-        }
+        f.read_exact(&mut b34)?;
 
         let mut iv: [u8; 16] = [0; 16];
-        match f.read_exact(&mut iv) {
-            Err(e) => {
-                eprintln!("Can't read IV: {}", e.description());
-                return false;
-            },
-            _ => (),
-        }
+        f.read_exact(&mut iv)?;
 
-        let k = match crypto::decrypt_block_ecb(&b12, &stretched) {
-            Ok(k) => k,
-            Err(e) => {
-                eprintln!("Can't decrypt K, probably invalid password: {}", e.description());
-                return false;
-            },
-        };
-        let l = match crypto::decrypt_block_ecb(&b34, &stretched) {
-            Ok(l) => l,
-            Err(e) => {
-                eprintln!("Can't decrypt L, probably invalid password: {}", e.description());
-                return false;
-            },
-        };
+        let k = crypto::decrypt_block_ecb(&b12, &stretched[..])?;
+        let l = crypto::decrypt_block_ecb(&b34, &stretched[..])?;
 
-        // read the remainder into vector and find the EOF marker
+        // the remainder is encrypted data, EOF marker and HMAC
         let mut d: Vec<u8> = Vec::new();
+        f.read_to_end(&mut d)?;
 
-        f.read_to_end(&mut d).expect("Can't read whole database file");
+        if d.len() < 16 + 32 {
+            return Err(Error::Truncated);
+        }
 
-        let eof_pos = match d.windows(16).position(|w| w == "PWS3-EOFPWS3-EOF".as_bytes()) {
-            Some(pos) => pos,
-            None => {
-                eprintln!("Can't find EOF marker");
-                return false;
-            },
-        };
+        let eof_pos = d.len() - 16 - 32;
+        if &d[eof_pos .. eof_pos+16] != b"PWS3-EOFPWS3-EOF" {
+            return Err(Error::MissingEof);
+        }
 
-        match crypto::decrypt_inplace(&mut d[0..eof_pos], &k, &iv) {
-            Err(e) => {
-                eprintln!("Can't decrypt data: {}", e.description());
-                return false;
-            }
-            _ => (),
+        if eof_pos % 16 != 0 {
+            return Err(Error::BadLength);
         }
 
-        let mut mac = crypto::HMAC::new(&l);
+        // plaintext never leaves this buffer unless it's parsed into fields
+        secret::lock(&d);
+        let result = self.parse(&mut d, eof_pos, &k[..], &l[..], &iv);
+        secret::wipe(&mut d[0..eof_pos]);
+        return result;
+    }
+
+    fn parse(&mut self, d: &mut [u8], eof_pos: usize, k: &[u8], l: &[u8], iv: &[u8]) -> Result<(), Error> {
+        crypto::decrypt_inplace(&mut d[0..eof_pos], k, iv)?;
+
+        let mut mac = crypto::HMAC::new(l);
         let mut c = Cursor::new(&d[0 .. eof_pos]);
 
         // header
-        match item::parse(&mut mac, &item::HEADER, &mut c) {
-            Some(hdr) => self.header = Some(hdr),
-            None => {
-                eprintln!("Can't read header item");
-                return false;
-            },
-        }
+        let header = match item::parse(&mut mac, &item::HEADER, &mut c)? {
+            Some(hdr) => hdr,
+            None => return Err(Error::Truncated),
+        };
 
         // entries
+        let mut items = Vec::new();
         loop {
-            match item::parse(&mut mac, &item::DATA, &mut c) {
-                Some(item) => self.items.push(item),
+            match item::parse(&mut mac, &item::DATA, &mut c)? {
+                Some(item) => items.push(item),
                 None => break,
             }
         }
 
-        // verify mac
+        // nothing is exposed until the data is known to be intact
         let expected_hmac = &d[eof_pos+16 .. eof_pos+16+32];
-        match mac.verify(expected_hmac) {
-            Err(e) => {
-                eprintln!("Can't verify HMAC: {}", e.description());
-                return false;
-            },
-            _ => (),
-        }
+        mac.verify(expected_hmac)?;
 
-        return true;
+        self.header = header;
+        self.items = items;
+
+        return Ok(());
     }
 
-    #[cfg(test)]
+    /// Number of entries in the database.
     pub fn len(&self) -> usize { self.items.len() }
 
+    pub fn is_empty(&self) -> bool { self.items.is_empty() }
+
     pub fn iter(&self) -> std::slice::Iter<item::Item> { self.items.iter() }
 
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<item::Item> { self.items.iter_mut() }
+
+    /// Calls `f` with the name ("Group.Title") of every entry.
     pub fn each(&self, f: &mut FnMut(&str, &item::Item)) {
         for i in self.iter() {
-            let mut name = String::new();
-
-            match i.get(item::Kind::Group) {
-                Some(g) => {
-                    match g {
-                        &item::Data::Text(ref v) => {
-                            name.push_str(v);
-                            name.push('.');
-                        },
-                        _ => panic!("Unexpected group type"),
-                    }
-                },
-                _ => (),
+            f(&i.name(), &i);
+        }
+    }
+
+    /// Returns entries whose name matches the given case insensitive
+    /// regular expression, an empty pattern matches all entries.
+    pub fn find(&self, pattern: &str) -> Result<Vec<&item::Item>, Error> {
+        let re = match Regex::new(&format!("(?i){}", pattern)) {
+            Ok(re) => re,
+            Err(e) => return Err(Error::InvalidPattern(e.to_string())),
+        };
+
+        return Ok(self.items.iter().filter(|i| re.is_match(&i.name())).collect());
+    }
+
+    /// Returns entries whose time field of the given kind (e.g. ExpiryTime)
+    /// is within `[from, to)`, a missing bound is unlimited. Entries without
+    /// such field never match.
+    pub fn find_time(&self, kind: item::Kind, from: Option<item::Time>, to: Option<item::Time>) -> Vec<&item::Item> {
+        return self.items.iter().filter(|i| {
+            match i.time(kind) {
+                Some(t) => from.map_or(true, |from| t >= from) && to.map_or(true, |to| t < to),
+                None => false,
             }
+        }).collect();
+    }
 
-            match i.get(item::Kind::Title) {
-                Some(t) => {
-                    match t {
-                        &item::Data::Text(ref v) => name.push_str(v),
-                        _ => panic!("Unexpected title type"),
-                    }
-                },
-                _ => (),
+    pub fn get(&self, uuid: &item::Uuid) -> Option<&item::Item> {
+        self.items.iter().find(|i| i.uuid().as_ref() == Some(uuid))
+    }
+
+    pub fn get_mut(&mut self, uuid: &item::Uuid) -> Option<&mut item::Item> {
+        match self.items.iter_mut().find(|i| i.uuid().as_ref() == Some(uuid)) {
+            Some(i) => {
+                if !self.changed.contains(uuid) {
+                    self.changed.push(*uuid);
+                }
+                return Some(i);
+            },
+            None => return None,
+        }
+    }
+
+    /// Adds a new entry, every entry needs an UUID (Password Safe uses it
+    /// to tell them apart) so a random one is generated if it's missing.
+    ///
+    /// Creation and modification times are set unless the entry already
+    /// has them (or times are not updated, see `set_update_times`).
+    pub fn insert(&mut self, mut item: item::Item) {
+        if item.uuid().is_none() {
+            item.insert(item::Kind::UUID, &item::Data::Raw(item::Uuid::new_v4().0.to_vec()));
+        }
+
+        if self.update_times {
+            let now = item::Data::Time(self.clock.now());
+            for kind in [item::Kind::CreateTime, item::Kind::ModifyTime].iter() {
+                if item.get(*kind).is_none() {
+                    item.insert(*kind, &now);
+                }
             }
+        }
+
+        self.items.push(item);
+    }
+
+    /// Changes an entry with `f` and updates its modification time, as well
+    /// as the password modification time if the password was changed.
+    /// Returns false if there is no such entry.
+    ///
+    /// A replaced password is added to the password history of the entry
+    /// when it's enabled, entries without one get the database default.
+    pub fn modify<F: FnOnce(&mut item::Item)>(&mut self, uuid: &item::Uuid, f: F) -> bool {
+        let now = self.clock.now();
+        let update_times = self.update_times;
+        let history = self.default_history();
+
+        let i = match self.get_mut(uuid) {
+            Some(i) => i,
+            None => return false,
+        };
+
+        let password = i.get(item::Kind::Password).cloned();
+        let set = i.time(item::Kind::PasswordModifyTime).or(i.time(item::Kind::CreateTime)).unwrap_or(now);
+        f(i);
+
+        if i.get(item::Kind::Password) != password.as_ref() {
+            match password {
+                Some(ref old) => record_history(i, old, set, history),
+                None => (),
+            }
+            if update_times {
+                i.insert(item::Kind::PasswordModifyTime, &item::Data::Time(now));
+            }
+        }
+
+        if update_times {
+            i.insert(item::Kind::ModifyTime, &item::Data::Time(now));
+        }
+        return true;
+    }
+
+    /// Updates access time of an entry after it was shown or copied,
+    /// returns false if the time wasn't changed.
+    pub fn touch(&mut self, uuid: &item::Uuid) -> bool {
+        if !self.update_times {
+            return false;
+        }
+
+        // not a change merge needs to keep the entry for
+        let now = item::Data::Time(self.clock.now());
+        match self.items.iter_mut().find(|i| i.uuid().as_ref() == Some(uuid)) {
+            Some(i) => {
+                i.insert(item::Kind::AccessTime, &now);
+                return true;
+            },
+            None => return false,
+        }
+    }
+
+    /// Replaces the clock used for create/modify/access and save times.
+    pub fn set_clock(&mut self, clock: Box<Clock>) {
+        self.clock = clock;
+    }
+
+    /// Whether `insert`, `modify` and `touch` maintain entry times (the
+    /// default), read-only workflows can turn it off.
+    pub fn set_update_times(&mut self, update: bool) {
+        self.update_times = update;
+    }
+
+    // History for entries that don't have one yet, from the
+    // SavePasswordHistory (bool 29) and NumPWHistoryDefault (int 8)
+    // preferences, off and 3 entries in Password Safe unless the database
+    // says otherwise.
+    fn default_history(&self) -> PasswordHistory {
+        let prefs = match self.header.get(item::Kind::NonDefaultPrefs) {
+            Some(&item::Data::Text(ref v)) => v.as_str(),
+            _ => "",
+        };
+
+        let mut h = PasswordHistory::new(pref(prefs, 'I', 8).unwrap_or(3) as usize);
+        h.enabled = pref(prefs, 'B', 29) == Some(1);
+        return h;
+    }
 
-            f(&name, &i);
+    pub fn remove(&mut self, uuid: &item::Uuid) -> Option<item::Item> {
+        match self.items.iter().position(|i| i.uuid().as_ref() == Some(uuid)) {
+            Some(pos) => Some(self.items.remove(pos)),
+            None => None,
         }
     }
 
-    pub fn each_re(&self, re: &Regex, f: &mut FnMut(&str, &item::Item)) {
-        self.each(&mut|name: &str, i: &item::Item| {
-            if re.is_match(&name) {
-                f(name, i);
+    /// Database header: name, description, preferences and so on.
+    pub fn header(&self) -> &item::Item { &self.header }
+
+    pub fn header_mut(&mut self) -> &mut item::Item { &mut self.header }
+
+    /// Named password policies, empty if there are none or they're
+    /// malformed.
+    pub fn policies(&self) -> policy::NamedPolicies {
+        match self.header.get(item::Kind::NamedPasswordPolicies) {
+            Some(&item::Data::Text(ref v)) => return policy::NamedPolicies::parse(v).unwrap_or_default(),
+            _ => return policy::NamedPolicies::default(),
+        }
+    }
+
+    pub fn set_policies(&mut self, p: &policy::NamedPolicies) {
+        if p.policies.is_empty() {
+            self.header.remove(item::Kind::NamedPasswordPolicies);
+        } else {
+            self.header.insert(item::Kind::NamedPasswordPolicies, &p.to_data());
+        }
+    }
+
+    /// Entry the field of the given kind of `i` comes from: the base entry
+    /// (followed further if it's an alias or shortcut itself) when `i` is
+    /// an alias or shortcut that takes the field from there, `i` otherwise.
+    pub fn resolve<'a>(&'a self, i: &'a item::Item, kind: item::Kind) -> Result<&'a item::Item, Error> {
+        let mut seen: Vec<*const item::Item> = Vec::new();
+        let mut e = i;
+
+        loop {
+            let r = match e.reference() {
+                Some(ref r) if r.follows(kind) => r.clone(),
+                _ => return Ok(e),
+            };
+
+            seen.push(e);
+            e = self.target(r.target())?;
+            if seen.iter().any(|&s| ptr::eq(s, e)) {
+                return Err(Error::ReferenceCycle(i.name()));
             }
-        });
+        }
     }
 
-    pub fn insert(&mut self, item: item::Item) {
-        self.items.push(item);
+    /// Fields of the entry with aliases and shortcuts resolved, see
+    /// `resolve`.
+    pub fn resolved_fields<'a>(&'a self, i: &'a item::Item) -> Result<Vec<&'a item::Field>, Error> {
+        // all the fields of a shortcut except its own come from the base
+        let mut kinds = Vec::new();
+        let mut seen: Vec<*const item::Item> = Vec::new();
+        let mut e = i;
+
+        loop {
+            for f in e.iter() {
+                if !kinds.contains(&f.kind()) {
+                    kinds.push(f.kind());
+                }
+            }
+
+            let r = match e.reference() {
+                Some(r @ reference::Reference::Shortcut(_)) => r,
+                _ => break,
+            };
+
+            seen.push(e);
+            e = self.target(r.target())?;
+            if seen.iter().any(|&s| ptr::eq(s, e)) {
+                return Err(Error::ReferenceCycle(i.name()));
+            }
+        }
+
+        let mut fields = Vec::new();
+        for kind in kinds {
+            let e = self.resolve(i, kind)?;
+            fields.extend(e.iter().filter(|f| f.kind() == kind));
+        }
+        return Ok(fields);
+    }
+
+    // Entry a reference points to.
+    fn target(&self, t: &reference::Target) -> Result<&item::Item, Error> {
+        let found = match *t {
+            reference::Target::Uuid(ref u) => self.get(u),
+            reference::Target::Name(ref group, ref title, ref user) => {
+                let text = |i: &item::Item, kind| match i.get(kind) {
+                    Some(&item::Data::Text(ref v)) => v.clone(),
+                    _ => String::new(),
+                };
+                self.items.iter().find(|i| {
+                    text(i, item::Kind::Group) == *group && text(i, item::Kind::Title) == *title &&
+                        (user.is_empty() || text(i, item::Kind::Username) == *user)
+                })
+            },
+        };
+
+        match found {
+            Some(i) => return Ok(i),
+            None => return Err(Error::BrokenReference(match *t {
+                reference::Target::Uuid(ref u) => u.to_string(),
+                reference::Target::Name(ref g, ref t, ref u) => format!("{}:{}:{}", g, t, u),
+            })),
+        }
+    }
+
+    /// Password policy and symbols (empty for the defaults) of the entry:
+    /// the named policy it refers to, its own policy or the default one.
+    pub fn policy(&self, i: &item::Item) -> Result<(policy::PasswordPolicy, String), Error> {
+        match i.get(item::Kind::PasswordPolicyName) {
+            Some(&item::Data::Text(ref name)) if !name.is_empty() => {
+                match self.policies().get(name) {
+                    Some(p) => return Ok((p.policy.clone(), p.symbols.clone())),
+                    None => return Err(Error::InvalidPolicy(format!("no policy named '{}'", name))),
+                }
+            },
+            _ => (),
+        }
+
+        let symbols = match i.get(item::Kind::PasswordSymbols) {
+            Some(&item::Data::Text(ref s)) => s.clone(),
+            _ => String::new(),
+        };
+        return Ok((i.policy().unwrap_or_default(), symbols));
+    }
+
+    /// Generates a password for the entry following its policy, see
+    /// `policy`.
+    pub fn generate_password(&self, i: &item::Item) -> Result<SecretString, Error> {
+        let (p, symbols) = self.policy(i)?;
+        return p.generate(Some(&symbols));
     }
 
+    /// Creates a new empty database, nothing is written until `save`.
     pub fn new(path: &str) -> V3 {
         crypto::init();
 
         return V3 {
-            path: path.to_string(),
+            path: Some(path.to_string()),
+            backups: 0,
+            lock: None,
+            locked_by: None,
+            disk_hash: None,
+            disk_uuids: Vec::new(),
+            changed: Vec::new(),
             salt: [0; 32],
-            iter: 100000,
-            header: None,
+            iter: DEFAULT_ITER,
+            header: item::new(),
             items: Vec::new(),
+            clock: Box::new(SystemClock),
+            update_times: true,
+            _guard: secret::Guard::new(),
         };
     }
 }
+
+/// Picks the number of password stretching iterations that takes about
+/// `ms` milliseconds on this machine, but no less than `MIN_ITER`.
+pub fn calibrate(ms: u64) -> u32 {
+    crypto::init();
+
+    // double until a single run is long enough to be measured reliably
+    let mut iter = MIN_ITER as u64;
+    loop {
+        let start = Instant::now();
+        crypto::stretch("calibrate", &[0; 32], iter as u32);
+        let elapsed = start.elapsed();
+        let us = elapsed.as_secs() * 1000000 + (elapsed.subsec_nanos() / 1000) as u64;
+
+        if us >= 50000 || iter >= std::u32::MAX as u64 {
+            let n = iter.saturating_mul(ms).saturating_mul(1000) / cmp::max(us, 1);
+            return cmp::min(cmp::max(n, MIN_ITER as u64), std::u32::MAX as u64) as u32;
+        }
+        iter = cmp::min(iter * 2, std::u32::MAX as u64);
+    }
+}
+
+// Keeps a replaced password (set at the given time) in the history,
+// starting with `default` if the entry has none. Malformed ones are left
+// alone.
+fn record_history(i: &mut item::Item, old: &item::Data, time: item::Time, default: PasswordHistory) {
+    let mut h = match i.history() {
+        Some(h) => h,
+        None if i.get(item::Kind::PasswordHistory).is_none() => default,
+        None => return,
+    };
+    if !h.enabled {
+        return;
+    }
+
+    let old = match *old {
+        item::Data::Sealed(ref s) => s.open(|b| String::from_utf8(b.to_vec()).ok()),
+        item::Data::Text(ref s) => Some(s.clone()),
+        _ => None,
+    };
+
+    match old {
+        Some(old) => {
+            h.push(time, SecretString::new(old));
+            i.set_history(&h);
+        },
+        None => (),
+    }
+}
+
+// Value of a boolean ('B') or integer ('I') preference in the header
+// preferences string: "B 29 1 I 8 5 S 6 \"text\" ...", string values are
+// quoted with a delimiter of choice.
+fn pref(prefs: &str, tp: char, id: u32) -> Option<u32> {
+    let mut c = prefs.chars();
+    loop {
+        let t = c.by_ref().find(|c| !c.is_whitespace())?;
+        let n: String = c.by_ref().skip_while(|c| c.is_whitespace()).take_while(|c| !c.is_whitespace()).collect();
+
+        if t == 'S' {
+            let delim = c.by_ref().find(|c| !c.is_whitespace())?;
+            c.by_ref().find(|c| *c == delim)?;
+            continue;
+        }
+
+        let v: String = c.by_ref().skip_while(|c| c.is_whitespace()).take_while(|c| !c.is_whitespace()).collect();
+        if t == tp && n.parse() == Ok(id) {
+            return v.parse().ok();
+        }
+    }
+}
+
+fn modify_time(i: &item::Item) -> u64 {
+    match i.time(item::Kind::ModifyTime) {
+        Some(t) => t.secs(),
+        None => 0,
+    }
+}
+
+pub fn backup_path(path: &Path, n: usize) -> PathBuf {
+    let stem = match path.file_stem() {
+        Some(stem) => stem.to_string_lossy().into_owned(),
+        None => String::new(),
+    };
+    return path.with_file_name(format!("{}_{:03}.ibak", stem, n));
+}
+
+// Creates a new file only the owner can read, so the database is never
+// exposed while it's being written.
+#[cfg(unix)]
+fn create_private(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    return OpenOptions::new().write(true).create_new(true).mode(0o600).open(path);
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> io::Result<File> {
+    return OpenOptions::new().write(true).create_new(true).open(path);
+}
+
+// Make sure the rename itself hits the disk, not supported everywhere
+// so errors are ignored.
+fn sync_dir(path: &Path) {
+    let dir = match path.parent() {
+        Some(d) if d.as_os_str().len() != 0 => d,
+        _ => Path::new("."),
+    };
+
+    match File::open(dir) {
+        Ok(d) => { d.sync_all().ok(); },
+        Err(_) => (),
+    }
+}
+
+pub(crate) fn username() -> String {
+    for var in ["USER", "LOGNAME", "USERNAME"].iter() {
+        match env::var(var) {
+            Ok(v) => return v,
+            Err(_) => (),
+        }
+    }
+    return String::new();
+}
+
+pub(crate) fn hostname() -> String {
+    let mut name = String::new();
+    match File::open("/proc/sys/kernel/hostname") {
+        Ok(mut f) => { f.read_to_string(&mut name).ok(); },
+        Err(_) => (),
+    }
+    if name.trim().is_empty() {
+        for var in ["HOSTNAME", "COMPUTERNAME"].iter() {
+            match env::var(var) {
+                Ok(v) => return v,
+                Err(_) => (),
+            }
+        }
+    }
+    return name.trim().to_string();
+}
@@ -0,0 +1,49 @@
+//! Implements the [V3](http://github.com/pwsafe/pwsafe/blob/master/docs/formatV3.txt)
+//! database format of the https://pwsafe.org.
+//!
+//! ```no_run
+//! let mut kc = pwsf::V3::open("simple.psafe3", "bogus12345").unwrap();
+//! for i in kc.find("test").unwrap() {
+//!     println!("{}", i.name());
+//! }
+//!
+//! let mut i = pwsf::Item::new();
+//! i.insert(pwsf::Kind::Title, &pwsf::Data::Text("title".to_string()));
+//! kc.insert(i);
+//! kc.save("bogus12345").unwrap();
+//! ```
+
+#[macro_use]
+extern crate lazy_static;
+extern crate byteorder;
+extern crate regex;
+extern crate rand;
+extern crate libc;
+
+mod crypto;
+pub mod error;
+pub mod history;
+pub mod item;
+pub mod keychain;
+pub mod lock;
+pub mod otp;
+pub mod passphrase;
+pub mod policy;
+pub mod reference;
+pub mod secret;
+
+#[cfg(feature = "fuzz")]
+#[doc(hidden)]
+pub mod fuzz;
+
+#[cfg(test)]
+mod tests;
+
+pub use error::Error;
+pub use history::PasswordHistory;
+pub use item::{Data, Field, Item, Kind, Time, Uuid};
+pub use keychain::V3;
+pub use otp::Totp;
+pub use passphrase::{Passphrase, Wordlist};
+pub use policy::{NamedPolicies, NamedPolicy, PasswordPolicy};
+pub use reference::{Reference, Target};
@@ -0,0 +1,140 @@
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use error::Error;
+use keychain;
+
+/// Whoever holds the lock, stored by Password Safe as "user@host:pid".
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Owner {
+    pub user: String,
+    pub host: String,
+    pub pid: u32,
+}
+
+impl Owner {
+    fn current() -> Owner {
+        return Owner {
+            user: keychain::username(),
+            host: keychain::hostname(),
+            pid: process::id(),
+        };
+    }
+
+    fn parse(s: &str) -> Owner {
+        let s = s.trim();
+        let (user, rest) = match s.rfind('@') {
+            Some(at) => (&s[..at], &s[at + 1..]),
+            None => (s, ""),
+        };
+        let (host, pid) = match rest.rfind(':') {
+            Some(colon) => (&rest[..colon], rest[colon + 1..].parse::<u32>().unwrap_or(0)),
+            None => (rest, 0),
+        };
+
+        return Owner {
+            user: user.to_string(),
+            host: host.to_string(),
+            pid,
+        };
+    }
+
+    // We can only tell that the owner is gone when it was running on
+    // the same machine.
+    fn is_stale(&self) -> bool {
+        if self.host != keychain::hostname() || self.pid == process::id() {
+            return false;
+        }
+        return !process_exists(self.pid);
+    }
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}:{}", self.user, self.host, self.pid)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn process_exists(pid: u32) -> bool {
+    pid != 0 && Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+// Signal 0 only checks whether the process is there, EPERM means it is
+// but belongs to somebody else. Pids that don't fit would address process
+// groups, so they're never running.
+#[cfg(all(unix, not(target_os = "linux")))]
+fn process_exists(pid: u32) -> bool {
+    use libc;
+
+    if pid == 0 || pid > libc::pid_t::max_value() as u32 {
+        return false;
+    }
+    if unsafe { libc::kill(pid as libc::pid_t, 0) } == 0 {
+        return true;
+    }
+    return io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+}
+
+// No way to tell elsewhere, so locks are never considered stale.
+#[cfg(not(unix))]
+fn process_exists(_pid: u32) -> bool {
+    true
+}
+
+/// Password Safe compatible lock of a database (foo.psafe3 -> foo.plk),
+/// removed when dropped.
+#[derive(Debug)]
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    /// Creates the lock file, fails with `Error::Locked` when somebody
+    /// else holds the lock or with `Error::StaleLock` when the lock was
+    /// left behind by a process that is no longer running (only detected
+    /// on Unix).
+    pub fn acquire(db_path: &str) -> Result<Lock, Error> {
+        let path = lock_path(Path::new(db_path));
+
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut f) => {
+                let lock = Lock{path};
+                f.write_all(Owner::current().to_string().as_bytes())?;
+                return Ok(lock);
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(Error::from(e)),
+        }
+
+        let mut owner = String::new();
+        fs::File::open(&path)?.read_to_string(&mut owner)?;
+
+        let owner = Owner::parse(&owner);
+        if owner.is_stale() {
+            return Err(Error::StaleLock(owner));
+        }
+        return Err(Error::Locked(owner));
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+pub fn lock_path(db_path: &Path) -> PathBuf {
+    db_path.with_extension("plk")
+}
+
+/// Removes somebody else's lock, should only be used for stale locks.
+pub fn break_lock(db_path: &str) -> Result<(), Error> {
+    fs::remove_file(lock_path(Path::new(db_path)))?;
+    return Ok(());
+}
@@ -1,50 +1,93 @@
-mod keychain;
-mod crypto;
-mod item;
-
-#[macro_use]
-extern crate lazy_static;
-extern crate byteorder;
+extern crate pwsf;
 extern crate getopts;
 extern crate rpassword;
-extern crate regex;
 extern crate clipboard;
-extern crate rand;
-
-#[cfg(test)]
-mod tests;
 
-use getopts::Options;
+use getopts::{Matches, Options};
 use std::env;
 use std::io;
-use std::io::Write;
-use regex::Regex;
+use std::io::{Read, Write};
+use std::fs::File;
+use pwsf::{keychain, item, lock};
+use pwsf::{NamedPolicy, Passphrase, PasswordPolicy, Reference, Target, Totp, Wordlist};
+use pwsf::secret::{Sealed, SecretString};
 use clipboard::{ClipboardProvider,ClipboardContext};
 use std::{thread, time};
 use std::path::Path;
 
 static mut STDIN_PASSWORD: bool = false;
+static mut BACKUPS: usize = 0;
+// password stretching iterations for new and passwd, 0 keeps the default
+static mut ITER: u32 = 0;
+static mut ITER_FLOOR: u32 = keychain::MIN_ITER;
+static mut TIME_FORMAT: TimeFormat = TimeFormat::Local;
+static mut READ_ONLY: bool = false;
+
+#[derive(Clone,Copy)]
+enum TimeFormat {
+    Local,
+    Utc,
+    Iso,
+}
 
 fn print_usage(exe: &str, opts: Options) {
     let brief = format!("Usage: {0} [options] <operation>
 
   {0} new
-    create new empty database
+    create new empty database, see --iter and --calibrate
 
   {0} passwd
-    change password of existing database
+    change password of existing database, see --iter and --calibrate, and
+    suggest a passphrase (see generate --words) or a random password
+
+  {0} add [--words N [--separator S] [--capitalize] [--digit] [--wordlist PATH]]
+    add new entry, an empty password is generated following the policy or
+    as a passphrase of N words
 
-  {0} add
-    add new entry
+  {0} alias <name regexp>
+    add new entry that uses the password of the matching entry
+
+  {0} shortcut <name regexp>
+    add new entry that uses all fields of the matching entry except group,
+    title and username
 
   {0} list [<name regexp>]
     list all entries or entries matching given regexp
 
   {0} copy <name regexp>
     copy password to clipboard, after user presses any key, copy username and exit
+    (updates access time of the entry unless --read-only is given)
 
   {0} show <name regexp>
     print all fields for matching entries
+    (updates access time of the entries unless --read-only is given)
+
+  {0} history <name regexp>
+    print previous passwords of matching entries with the time they were set
+
+  {0} generate [--entry <name regexp> | --policy <name>] [--chars CLASSES] [--length N] [--symbols CHARS]
+    print a random password following the policy and symbols of the entry,
+    the named policy or the default policy, --chars, --length and --symbols
+    override them and an empty --symbols leaves symbols out
+
+  {0} generate --words N [--separator S] [--capitalize] [--digit] [--wordlist PATH]
+    print a passphrase of N words from the EFF large wordlist
+    (https://www.eff.org/dice), by default ~/.pwsafe/eff_large_wordlist.txt
+
+  {0} otp <name regexp> [--copy]
+    print the current one-time password (TOTP) of matching entries with the
+    time it stays valid for, or copy it to clipboard until it expires
+
+  {0} otp <name regexp> --import <otpauth://totp/...>
+    store the two-factor key and parameters of the URI in the matching entry
+
+  {0} policy list
+  {0} policy add <name> [--chars CLASSES] [--length N] [--symbols CHARS]
+  {0} policy remove <name>
+    manage named password policies, CLASSES is a comma separated list of
+    lower, upper, digits and symbols (with optional minimum count, e.g.
+    digits=2), hex, easy-vision and pronounceable; add replaces the policy
+    with the same name
 
   Examples:
     $ echo -n bogus12345 | {0} -p ./simple.psafe3 -S list
@@ -69,9 +112,14 @@ fn print_usage(exe: &str, opts: Options) {
     print!("{}", opts.usage(&brief));
 }
 
-fn case_insensitive_re(args: &[String]) -> Regex {
-    let re = format!("(?i){}", &args.join(""));
-    return Regex::new(&re).expect("Can't parse regular expression");
+fn find<'a>(kc: &'a keychain::V3, args: &[String]) -> Vec<&'a item::Item> {
+    match kc.find(&args.join("")) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", e);
+            Vec::new()
+        },
+    }
 }
 
 fn op_new(db_path: &str) {
@@ -89,12 +137,15 @@ fn op_new(db_path: &str) {
     }
 
     let mut kc = keychain::V3::new(&db_path);
-    kc.save(&password);
+    set_iter(&mut kc);
+    save(&mut kc, &password);
 }
 
-fn op_passwd(db_path: &str) {
+fn op_passwd(db_path: &str, m: &Matches) {
     let password = ask_password("Current password: ");
 
+    suggest_password(m);
+
     let newpassword = ask_password("New Password: ");
     let newpassword2 = ask_password("Retype new password: ");
 
@@ -103,52 +154,108 @@ fn op_passwd(db_path: &str) {
         return;
     }
 
-    match keychain::V3::open(&db_path, &password) {
-        Some(mut kc) => kc.save(&newpassword),
-        None => false,
-    };
+    match open_for_write(&db_path, &password) {
+        Some(mut kc) => {
+            set_iter(&mut kc);
+            save(&mut kc, &newpassword);
+        },
+        None => (),
+    }
 }
 
-fn op_add(db_path: &str) {
+fn op_add(db_path: &str, m: &Matches) {
     let password = ask_password("Password: ");
 
-    match keychain::V3::open(&db_path, &password) {
+    match open_for_write(&db_path, &password) {
         Some(mut kc) => {
-            let mut item = item::new();
+            if kc.read_only() {
+                return;
+            }
+
+            let mut item = item::Item::new();
 
             let g = ask("Group");
             let t = ask("Title");
             let u = ask("Username");
-            let p = ask("Password");
+            let policy = ask("Password policy name (empty for none)");
+            let p = ask("Password (empty to generate)");
             let n = ask("Notes");
 
+            if policy != "" && kc.policies().get(&policy).is_none() {
+                eprintln!("No policy named '{}'", policy);
+                return;
+            }
+
             if g != "" {
                 item.insert(item::Kind::Group, &item::Data::Text(g));
             }
             item.insert(item::Kind::Title, &item::Data::Text(t));
             item.insert(item::Kind::Username, &item::Data::Text(u));
-            item.insert(item::Kind::Password, &item::Data::Text(p));
             item.insert(item::Kind::Notes, &item::Data::Text(n));
+            if policy != "" {
+                item.insert(item::Kind::PasswordPolicyName, &item::Data::Text(policy));
+            }
+
+            if p != "" {
+                item.insert(item::Kind::Password, &item::Data::Text(p));
+            } else if m.opt_present("words") {
+                let (p, list) = match (passphrase_options(m), wordlist(m)) {
+                    (Some(p), Some(list)) => (p, list),
+                    _ => return,
+                };
+                match p.generate(&list) {
+                    Ok(s) => {
+                        println!("Generated passphrase with about {:.0} bits of entropy", p.entropy(&list));
+                        item.insert(item::Kind::Password, &item::Data::Sealed(Sealed::new(s.as_bytes())));
+                    },
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return;
+                    },
+                }
+            } else {
+                let generated = kc.policy(&item).and_then(|(policy, symbols)| {
+                    let p = policy.generate(Some(&symbols))?;
+                    println!("Generated password with about {:.0} bits of entropy", policy.entropy(Some(&symbols)));
+                    return Ok(p);
+                });
+                match generated {
+                    Ok(p) => item.insert(item::Kind::Password, &item::Data::Sealed(Sealed::new(p.as_bytes()))),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return;
+                    },
+                }
+            }
             kc.insert(item);
 
-            kc.save(&password);
+            save(&mut kc, &password);
         },
         None => (),
     };
 }
 
 fn op_list(kc: &keychain::V3, args: &[String]) {
-    kc.each_re(&case_insensitive_re(args), &mut |name: &str, _: &item::Item| {
-        println!("{}", name);
-    });
+    for i in find(kc, args) {
+        println!("{}", i.name());
+    }
 }
 
-fn op_copy(kc: &keychain::V3, args: &[String]) {
+fn op_copy(kc: &mut keychain::V3, args: &[String], password: &str) {
     let mut v = Vec::new();
-    kc.each_re(&case_insensitive_re(args), &mut |name: &str, i: &item::Item| {
+    for i in find(kc, args) {
         let mut user = String::new();
         let mut pass = String::new();
 
+        // aliases and shortcuts use the password of their base entry
+        let base = match kc.resolve(i, item::Kind::Password) {
+            Ok(base) => base,
+            Err(e) => {
+                eprintln!("{}: {}", i.name(), e);
+                continue;
+            },
+        };
+
         match i.get(item::Kind::Username) {
             Some(u) => {
                 match u {
@@ -159,18 +266,16 @@ fn op_copy(kc: &keychain::V3, args: &[String]) {
             _ => eprintln!("Username missing, assuming empty string"),
         }
 
-        match i.get(item::Kind::Password) {
-            Some(p) => {
-                match p {
-                    &item::Data::Text(ref v) => pass.push_str(v),
-                    _ => panic!("Password has wrong type"),
-                }
-            },
-            _ => eprintln!("Password missing, assuming empty string"),
-        }
+        base.reveal(item::Kind::Password, |p| {
+            match p {
+                Some(&item::Data::Text(ref v)) => pass.push_str(v),
+                Some(_) => panic!("Password has wrong type"),
+                None => eprintln!("Password missing, assuming empty string"),
+            }
+        });
 
-        v.push((name.to_string(), user, pass));
-    });
+        v.push((i.name(), user, SecretString::new(pass), i.uuid()));
+    }
 
     let mut selected = 0;
 
@@ -195,7 +300,13 @@ fn op_copy(kc: &keychain::V3, args: &[String]) {
     }
 
     match v.get(selected) {
-        Some(s) => clipboard_copy(&s.1, &s.2),
+        Some(s) => {
+            match s.3 {
+                Some(ref uuid) if kc.touch(uuid) => save_access(kc, password),
+                _ => (),
+            }
+            clipboard_copy(&s.1, &s.2);
+        },
         None => {
             eprintln!("Invalid selection");
             return;
@@ -203,16 +314,686 @@ fn op_copy(kc: &keychain::V3, args: &[String]) {
     }
 }
 
-fn op_show(kc: &keychain::V3, args: &[String]) {
-    kc.each_re(&case_insensitive_re(args), &mut |name: &str, i: &item::Item| {
-        println!("{}:", name);
-        for (k, v) in i.iter() {
-            if *k != item::Kind::UUID {
-                println!("\t{:?}: {}", k, v.to_string());
+fn op_show(kc: &mut keychain::V3, args: &[String], password: &str) {
+    let mut uuids = Vec::new();
+    for i in find(kc, args) {
+        uuids.extend(i.uuid());
+        println!("{}:", i.name());
+
+        // with the fields of the base entry for aliases and shortcuts
+        let fields = match kc.resolved_fields(i) {
+            Ok(fields) => fields,
+            Err(e) => {
+                eprintln!("\t{}", e);
+                i.iter().collect()
+            },
+        };
+        match i.reference() {
+            Some(ref r) => println!("\t{}", show_reference(kc, r)),
+            None => (),
+        }
+
+        for f in fields {
+            if f.kind() != item::Kind::UUID {
+                f.reveal(|d| println!("\t{:?}: {}", f.kind(), show_field(f.kind(), d)));
             }
         }
         println!("");
-    });
+    }
+
+    let mut touched = false;
+    for uuid in uuids.iter() {
+        touched |= kc.touch(uuid);
+    }
+    if touched {
+        save_access(kc, password);
+    }
+}
+
+fn op_history(kc: &keychain::V3, args: &[String]) {
+    for i in find(kc, args) {
+        println!("{}:", i.name());
+        match i.history() {
+            Some(ref h) if !h.entries.is_empty() => {
+                for e in h.entries.iter().rev() {
+                    println!("\t{}: {}", show_time(e.time), &*e.password);
+                }
+            },
+            Some(ref h) if !h.enabled => println!("\t(history disabled)"),
+            _ => println!("\t(no previous passwords)"),
+        }
+        println!("");
+    }
+}
+
+fn show_reference(kc: &keychain::V3, r: &Reference) -> String {
+    let (kind, target) = match *r {
+        Reference::Alias(ref t) => ("Alias", t),
+        Reference::Shortcut(ref t) => ("Shortcut", t),
+    };
+
+    let base = match *target {
+        Target::Uuid(ref u) => kc.get(u).map(|i| i.name()).unwrap_or(u.to_string()),
+        Target::Name(ref g, ref t, ref u) => format!("{}:{}:{}", g, t, u),
+    };
+    return format!("{} of {}", kind, base);
+}
+
+// Adds an alias or shortcut entry referring to the one matching args.
+fn op_reference(db_path: &str, args: &[String], shortcut: bool) {
+    let password = ask_password("Password: ");
+
+    match open_for_write(&db_path, &password) {
+        Some(ref mut kc) if !kc.read_only() => {
+            let r = {
+                let v = find(kc, args);
+                if v.len() != 1 {
+                    eprintln!("Expected one entry matching '{}', found {}", args.join(""), v.len());
+                    return;
+                }
+                if v[0].reference().is_some() {
+                    eprintln!("{} is an alias or shortcut itself", v[0].name());
+                    return;
+                }
+
+                let target = match v[0].uuid() {
+                    Some(u) => Target::Uuid(u),
+                    None => {
+                        eprintln!("{} has no UUID", v[0].name());
+                        return;
+                    },
+                };
+                if shortcut { Reference::Shortcut(target) } else { Reference::Alias(target) }
+            };
+
+            let mut item = item::Item::new();
+
+            let g = ask("Group");
+            let t = ask("Title");
+            let u = ask("Username");
+
+            if g != "" {
+                item.insert(item::Kind::Group, &item::Data::Text(g));
+            }
+            item.insert(item::Kind::Title, &item::Data::Text(t));
+            item.insert(item::Kind::Username, &item::Data::Text(u));
+            item.insert(item::Kind::Password, &item::Data::Text(r.to_string()));
+            kc.insert(item);
+
+            save(kc, &password);
+        },
+        _ => (),
+    }
+}
+
+fn op_generate(db_path: &str, m: &Matches) {
+    if m.opt_present("words") {
+        let p = match passphrase_options(m) {
+            Some(p) => p,
+            None => return,
+        };
+        let list = match wordlist(m) {
+            Some(list) => list,
+            None => return,
+        };
+
+        match p.generate(&list) {
+            Ok(s) => {
+                println!("{}", &*s);
+                eprintln!("About {:.0} bits of entropy", p.entropy(&list));
+            },
+            Err(e) => eprintln!("{}", e),
+        }
+        return;
+    }
+
+    let mut policy = PasswordPolicy::default();
+    let mut symbols = String::new();
+
+    if m.opt_present("entry") || m.opt_present("policy") {
+        let kc = match open(db_path, &ask_password("Password: ")) {
+            Some(kc) => kc,
+            None => return,
+        };
+
+        match m.opt_str("entry") {
+            Some(e) => {
+                let v = find(&kc, &[e.clone()]);
+                if v.len() != 1 {
+                    eprintln!("Expected one entry matching '{}', found {}", e, v.len());
+                    return;
+                }
+
+                match kc.policy(v[0]) {
+                    Ok((p, s)) => {
+                        policy = p;
+                        symbols = s;
+                    },
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return;
+                    },
+                }
+            },
+            None => (),
+        }
+
+        match m.opt_str("policy") {
+            Some(name) => match kc.policies().get(&name) {
+                Some(p) => {
+                    policy = p.policy.clone();
+                    symbols = p.symbols.clone();
+                },
+                None => {
+                    eprintln!("No policy named '{}'", name);
+                    return;
+                },
+            },
+            None => (),
+        }
+    }
+
+    if !policy_options(m, &mut policy, &mut symbols) {
+        return;
+    }
+
+    match policy.generate(Some(&symbols)) {
+        Ok(p) => {
+            println!("{}", &*p);
+            eprintln!("About {:.0} bits of entropy", policy.entropy(Some(&symbols)));
+        },
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+// Passphrase from --words, --separator, --capitalize and --digit, None if
+// they're invalid.
+fn passphrase_options(m: &Matches) -> Option<Passphrase> {
+    let mut p = Passphrase::default();
+
+    match m.opt_str("words") {
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) => p.words = n,
+            Err(_) => {
+                eprintln!("Invalid number of words '{}'", n);
+                return None;
+            },
+        },
+        None => (),
+    }
+
+    match m.opt_str("separator") {
+        Some(s) => p.separator = s,
+        None => (),
+    }
+    p.capitalize = m.opt_present("capitalize");
+    p.digit = m.opt_present("digit");
+
+    return Some(p);
+}
+
+fn wordlist_path(m: &Matches) -> Option<String> {
+    match m.opt_str("wordlist") {
+        Some(p) => return Some(p),
+        None => return env::home_dir().map(|path| format!("{}/.pwsafe/eff_large_wordlist.txt", path.display())),
+    }
+}
+
+// Words from --wordlist or ~/.pwsafe/eff_large_wordlist.txt.
+fn wordlist(m: &Matches) -> Option<Wordlist> {
+    let path = match wordlist_path(m) {
+        Some(p) => p,
+        None => {
+            eprintln!("Can't get home directory, use --wordlist");
+            return None;
+        },
+    };
+
+    let mut s = String::new();
+    match File::open(&path).and_then(|mut f| f.read_to_string(&mut s)) {
+        Ok(_) => (),
+        Err(e) => {
+            eprintln!("Can't read wordlist '{}': {}", path, e);
+            return None;
+        },
+    }
+
+    match Wordlist::parse(&s) {
+        Some(list) => return Some(list),
+        None => {
+            eprintln!("Wordlist '{}' has too few words", path);
+            return None;
+        },
+    }
+}
+
+// Prints a passphrase (or a random password if there is no wordlist) to
+// pick as the new password.
+fn suggest_password(m: &Matches) {
+    let has_wordlist = match wordlist_path(m) {
+        Some(p) => Path::new(&p).exists(),
+        None => false,
+    };
+    if !has_wordlist {
+        eprintln!("No wordlist found, suggesting a random password instead of a passphrase (see --wordlist)");
+    }
+
+    let suggestion = match (passphrase_options(m), if has_wordlist { wordlist(m) } else { None }) {
+        (Some(p), Some(list)) => p.generate(&list).map(|s| (s, p.entropy(&list))),
+        _ => {
+            let p = PasswordPolicy{length: 20, ..Default::default()};
+            p.generate(None).map(|s| (s, p.entropy(None)))
+        },
+    };
+
+    match suggestion {
+        Ok((s, bits)) => println!("Suggested password (about {:.0} bits of entropy): {}", bits, &*s),
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+// Applies --chars, --length and --symbols, returns false if they're invalid.
+fn policy_options(m: &Matches, policy: &mut PasswordPolicy, symbols: &mut String) -> bool {
+    match m.opt_str("chars") {
+        Some(chars) => {
+            *policy = PasswordPolicy{
+                lowercase: false,
+                uppercase: false,
+                digits: false,
+                symbols: false,
+                min_lowercase: 0,
+                min_uppercase: 0,
+                min_digits: 0,
+                min_symbols: 0,
+                ..policy.clone()
+            };
+
+            for c in chars.split(',') {
+                let mut kv = c.splitn(2, '=');
+                let class = kv.next().unwrap_or("");
+                let min = match kv.next().map(|n| n.parse::<usize>()) {
+                    Some(Ok(n)) => n,
+                    Some(Err(_)) => {
+                        eprintln!("Invalid minimum count in '{}'", c);
+                        return false;
+                    },
+                    None => 1,
+                };
+
+                match class {
+                    "lower" => { policy.lowercase = true; policy.min_lowercase = min; },
+                    "upper" => { policy.uppercase = true; policy.min_uppercase = min; },
+                    "digits" => { policy.digits = true; policy.min_digits = min; },
+                    "symbols" => { policy.symbols = true; policy.min_symbols = min; },
+                    "hex" => policy.hex = true,
+                    "easy-vision" => policy.easy_vision = true,
+                    "pronounceable" => policy.pronounceable = true,
+                    _ => {
+                        eprintln!("Invalid character class '{}'", class);
+                        return false;
+                    },
+                }
+            }
+        },
+        None => (),
+    }
+
+    match m.opt_str("length") {
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) => policy.length = n,
+            Err(_) => {
+                eprintln!("Invalid password length '{}'", n);
+                return false;
+            },
+        },
+        None => (),
+    }
+
+    match m.opt_str("symbols") {
+        Some(ref s) if s.is_empty() => policy.symbols = false,
+        Some(s) => {
+            policy.symbols = true;
+            *symbols = s;
+        },
+        None => (),
+    }
+
+    return true;
+}
+
+fn op_policy(db_path: &str, m: &Matches) {
+    let args = &m.free[1..];
+    match (args.get(0).map(|s| s.as_str()), args.get(1)) {
+        (Some("list"), None) => {
+            let kc = match open(db_path, &ask_password("Password: ")) {
+                Some(kc) => kc,
+                None => return,
+            };
+
+            for p in kc.policies().policies.iter() {
+                if p.symbols.is_empty() {
+                    println!("{}: {}", p.name, p.policy);
+                } else {
+                    println!("{}: {}, symbols {}", p.name, p.policy, p.symbols);
+                }
+            }
+        },
+        (Some("add"), Some(name)) => {
+            let mut policy = PasswordPolicy::default();
+            let mut symbols = String::new();
+            if !policy_options(m, &mut policy, &mut symbols) {
+                return;
+            }
+            match policy.generate(Some(&symbols)) {
+                Ok(_) => (),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return;
+                },
+            }
+
+            let password = ask_password("Password: ");
+            match open_for_write(db_path, &password) {
+                Some(ref mut kc) if !kc.read_only() => {
+                    let mut p = kc.policies();
+                    p.insert(NamedPolicy{name: name.to_string(), policy, symbols});
+                    kc.set_policies(&p);
+                    save(kc, &password);
+                },
+                _ => (),
+            }
+        },
+        (Some("remove"), Some(name)) => {
+            let password = ask_password("Password: ");
+            match open_for_write(db_path, &password) {
+                Some(ref mut kc) if !kc.read_only() => {
+                    let mut p = kc.policies();
+                    if !p.remove(name) {
+                        eprintln!("No policy named '{}'", name);
+                        return;
+                    }
+                    kc.set_policies(&p);
+
+                    let users = kc.iter().filter(|i| match i.get(item::Kind::PasswordPolicyName) {
+                        Some(&item::Data::Text(ref v)) => v == name,
+                        _ => false,
+                    }).count();
+                    if users > 0 {
+                        eprintln!("Warning: {} entries still refer to policy '{}'", users, name);
+                    }
+
+                    save(kc, &password);
+                },
+                _ => (),
+            }
+        },
+        _ => eprintln!("Expected 'policy list', 'policy add <name>' or 'policy remove <name>'"),
+    }
+}
+
+fn op_otp(db_path: &str, m: &Matches) {
+    let args = &m.free[1..];
+
+    match m.opt_str("import") {
+        Some(uri) => {
+            let totp = match Totp::from_uri(&uri) {
+                Ok(t) => t,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return;
+                },
+            };
+
+            let password = ask_password("Password: ");
+            match open_for_write(db_path, &password) {
+                Some(ref mut kc) if !kc.read_only() => {
+                    let uuids: Vec<Option<item::Uuid>> = find(kc, args).iter().map(|i| i.uuid()).collect();
+                    if uuids.len() != 1 {
+                        eprintln!("Expected one entry matching '{}', found {}", args.join(""), uuids.len());
+                        return;
+                    }
+
+                    match uuids[0] {
+                        Some(ref uuid) => {
+                            kc.modify(uuid, |i| i.set_totp(&totp));
+                            save(kc, &password);
+                        },
+                        None => eprintln!("Entry has no UUID"),
+                    }
+                },
+                _ => (),
+            }
+            return;
+        },
+        None => (),
+    }
+
+    let kc = match open(db_path, &ask_password("Password: ")) {
+        Some(kc) => kc,
+        None => return,
+    };
+
+    let now = item::Time::now().secs();
+    let codes: Vec<(String, String, u64)> = find(&kc, args).iter().filter_map(|i| {
+        i.totp().map(|t| (i.name(), t.code(now), t.remaining(now)))
+    }).collect();
+
+    if codes.is_empty() {
+        eprintln!("No entries matching '{}' with a two-factor key found", args.join(""));
+        return;
+    }
+
+    if m.opt_present("copy") {
+        if codes.len() > 1 {
+            eprintln!("More than one entry matches '{}'", args.join(""));
+            return;
+        }
+
+        let (ref name, ref code, remaining) = codes[0];
+        let mut ctx: ClipboardContext = ClipboardProvider::new().expect("Can't obtain clipboard context");
+        ctx.set_contents(code.to_owned()).expect("Can't paste code into clipboard");
+        println!("Code of {} is now in your clipboard, it's valid for {} more seconds", name, remaining);
+
+        thread::sleep(time::Duration::from_secs(remaining));
+        ctx.set_contents("".to_owned()).expect("Can't clear clipboard");
+        return;
+    }
+
+    for &(ref name, ref code, remaining) in codes.iter() {
+        println!("{}: {} (valid for {} more seconds)", name, code, remaining);
+    }
+}
+
+fn show_field(kind: item::Kind, data: &item::Data) -> String {
+    match (kind, data) {
+        (item::Kind::AttachmentRef, &item::Data::Raw(ref v)) if v.len() == 16 =>
+            item::Uuid::from_slice(v).unwrap().to_string(),
+        (item::Kind::TwoFactorKey, &item::Data::Raw(ref v)) =>
+            base32(v),
+        (item::Kind::TotpConfig, &item::Data::Byte(v)) =>
+            match Totp::algorithm_from_config(v) {
+                Some(a) => format!("{:?}", a).to_uppercase(),
+                None => format!("unknown ({})", v),
+            },
+        (item::Kind::PasswordExpiryInterval, &item::Data::Int(v)) =>
+            format!("{} days", v),
+        (item::Kind::KeyboardShortcut, &item::Data::Int(v)) =>
+            shortcut(v),
+        (_, &item::Data::Time(t)) =>
+            show_time(t),
+        (item::Kind::Protected, &item::Data::Byte(v)) =>
+            (if v != 0 { "yes" } else { "no" }).to_string(),
+        (_, &item::Data::Raw(ref v)) =>
+            v.iter().map(|b| format!("{:02x}", b)).collect(),
+        (_, d) =>
+            d.to_string(),
+    }
+}
+
+fn show_time(t: item::Time) -> String {
+    match unsafe { TIME_FORMAT } {
+        TimeFormat::Local => t.to_local_string(),
+        TimeFormat::Utc => t.to_utc_string(),
+        TimeFormat::Iso => t.to_iso8601(),
+    }
+}
+
+// RFC 4648 base32, the usual way to show two-factor keys.
+fn base32(v: &[u8]) -> String {
+    const ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    let mut s = String::new();
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for &b in v {
+        acc = (acc << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            s.push(ALPHABET[((acc >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        s.push(ALPHABET[((acc << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    return s;
+}
+
+// Password Safe keeps modifiers in the high 16 bits and the virtual key
+// code in the low ones.
+fn shortcut(v: u32) -> String {
+    let mut s = String::new();
+    let modifiers = v >> 16;
+    for &(bit, name) in [(0x02, "Ctrl+"), (0x01, "Alt+"), (0x04, "Shift+"), (0x10, "Meta+"), (0x20, "Win+"), (0x40, "Cmd+")].iter() {
+        if modifiers & bit != 0 {
+            s.push_str(name);
+        }
+    }
+
+    let key = (v & 0xffff) as u8 as char;
+    if v & 0xffff < 0x80 && key.is_ascii_alphanumeric() {
+        s.push(key);
+    } else {
+        s.push_str(&format!("0x{:02x}", v & 0xffff));
+    }
+    return s;
+}
+
+// Takes the database lock, falls back to read-only mode when somebody else
+// holds it and removes locks left behind by dead processes.
+fn open_for_write(db_path: &str, password: &str) -> Option<keychain::V3> {
+    let kc = match keychain::V3::open_locked(db_path, password) {
+        Err(pwsf::Error::StaleLock(owner)) => {
+            println!("Database is locked by {}, which is no longer running", owner);
+            if ask("Remove the stale lock? [y/n]") != "y" {
+                return None;
+            }
+            match lock::break_lock(db_path) {
+                Err(e) => {
+                    eprintln!("Can't remove stale lock: {}", e);
+                    return None;
+                },
+                _ => (),
+            }
+            keychain::V3::open_locked(db_path, password)
+        },
+        r => r,
+    };
+
+    match kc {
+        Ok(kc) => {
+            check_iter(&kc);
+            match kc.locked_by() {
+                Some(owner) => eprintln!("Database is locked by {}, opened read-only", owner),
+                None => (),
+            }
+            return Some(kc);
+        },
+        Err(e) => {
+            eprintln!("Can't open database: {}", e);
+            return None;
+        },
+    }
+}
+
+fn open(db_path: &str, password: &str) -> Option<keychain::V3> {
+    match keychain::V3::open(&db_path, password) {
+        Ok(kc) => {
+            check_iter(&kc);
+            return Some(kc);
+        },
+        Err(e) => {
+            eprintln!("Can't open database: {}", e);
+            return None;
+        },
+    }
+}
+
+// copy and show update access times, which needs the database lock,
+// unless --read-only is given
+fn open_for_access(db_path: &str, password: &str) -> Option<keychain::V3> {
+    if unsafe { READ_ONLY } {
+        let mut kc = open(db_path, password);
+        match kc {
+            Some(ref mut kc) => kc.set_update_times(false),
+            None => (),
+        }
+        return kc;
+    }
+    return open_for_write(db_path, password);
+}
+
+// access times alone aren't worth pushing a backup out of rotation
+fn save_access(kc: &mut keychain::V3, password: &str) {
+    if unsafe { READ_ONLY } || kc.read_only() {
+        return;
+    }
+    save_backups(kc, password, 0);
+}
+
+fn check_iter(kc: &keychain::V3) {
+    let floor = unsafe { ITER_FLOOR };
+
+    if kc.iterations() < keychain::MIN_ITER {
+        eprintln!("Warning: database uses {} password iterations, less than the V3 minimum of {}", kc.iterations(), keychain::MIN_ITER);
+    } else if kc.iterations() < floor {
+        eprintln!("Warning: database uses {} password iterations, less than {}", kc.iterations(), floor);
+    } else {
+        return;
+    }
+    eprintln!("Use passwd with --iter or --calibrate to increase it");
+}
+
+fn set_iter(kc: &mut keychain::V3) {
+    let iter = unsafe { ITER };
+    if iter != 0 {
+        kc.set_iterations(iter);
+    }
+}
+
+fn save(kc: &mut keychain::V3, password: &str) {
+    save_backups(kc, password, unsafe { BACKUPS });
+}
+
+fn save_backups(kc: &mut keychain::V3, password: &str, backups: usize) {
+    kc.set_backups(backups);
+
+    let r = match kc.save(password) {
+        Err(pwsf::Error::Modified) => {
+            println!("Database was changed on disk since it was opened");
+            match ask("[a]bort, [o]verwrite or [m]erge changes").as_ref() {
+                "o" => kc.save_with(password, keychain::Conflict::Overwrite),
+                "m" => kc.save_with(password, keychain::Conflict::Merge),
+                _ => Err(pwsf::Error::Modified),
+            }
+        },
+        r => r,
+    };
+
+    match r {
+        Err(e) => eprintln!("Can't save database: {}", e),
+        _ => (),
+    }
 }
 
 fn read_stdin_number() -> Option<usize> {
@@ -254,42 +1035,59 @@ fn clipboard_copy(user: &str, pass: &str) {
     ctx.set_contents("".to_owned()).expect("Can't clear clipboard");
 }
 
-fn run_op(db_path: &str, op: &Vec<String>) -> bool {
+fn run_op(db_path: &str, m: &Matches) -> bool {
+    let op = &m.free;
     match op[0].as_ref() {
         "new" => op_new(db_path),
-        "passwd" => op_passwd(db_path),
-        "add" => op_add(db_path),
+        "passwd" => op_passwd(db_path, m),
+        "add" => op_add(db_path, m),
         "list" => {
-            match keychain::V3::open(&db_path, &ask_password("Password: ")) {
+            match open(db_path, &ask_password("Password: ")) {
                 Some(kc) => op_list(&kc, &op[1..]),
-                None => {},
+                None => (),
             }
         },
         "copy" => {
-            match keychain::V3::open(&db_path, &ask_password("Password: ")) {
-                Some(kc) => op_copy(&kc, &op[1..]),
-                None => {},
+            let password = ask_password("Password: ");
+            match open_for_access(db_path, &password) {
+                Some(mut kc) => op_copy(&mut kc, &op[1..], &password),
+                None => (),
             }
         },
         "show" => {
-            match keychain::V3::open(&db_path, &ask_password("Password: ")) {
-                Some(kc) => op_show(&kc, &op[1..]),
-                None => {},
+            let password = ask_password("Password: ");
+            match open_for_access(db_path, &password) {
+                Some(mut kc) => op_show(&mut kc, &op[1..], &password),
+                None => (),
             }
         },
+        "history" => {
+            match open(db_path, &ask_password("Password: ")) {
+                Some(kc) => op_history(&kc, &op[1..]),
+                None => (),
+            }
+        },
+        "generate" => op_generate(db_path, m),
+        "policy" => op_policy(db_path, m),
+        "otp" => op_otp(db_path, m),
+        "alias" => op_reference(db_path, &op[1..], false),
+        "shortcut" => op_reference(db_path, &op[1..], true),
         _ => return false,
     }
 
     return true;
 }
 
-fn ask_password(query: &str) -> String {
+fn ask_password(query: &str) -> SecretString {
     if unsafe { STDIN_PASSWORD } {
         let mut password = String::new();
         io::stdin().read_line(&mut password).expect("Can't read password from stdin");
-        return password.trim_right().to_string();
+        // truncate in place, so there is no copy to wipe
+        let len = password.trim_right().len();
+        password.truncate(len);
+        return SecretString::new(password);
     } else {
-        return rpassword::prompt_password_stdout(query).expect("Can't query password");
+        return SecretString::new(rpassword::prompt_password_stdout(query).expect("Can't query password"));
     }
 }
 
@@ -297,6 +1095,24 @@ fn main() {
     let mut opts = Options::new();
     opts.optopt("p", "db-path", "path to the database", "PATH");
     opts.optflag("S", "stdin", "read password from stdin");
+    opts.optopt("b", "backups", "number of backups (PATH_001.ibak, ...) to keep on save", "N");
+    opts.optopt("i", "iter", "number of password stretching iterations for new and passwd", "N");
+    opts.optopt("", "calibrate", "pick number of iterations that takes MS milliseconds on this machine", "MS");
+    opts.optopt("", "min-iter", "warn when a database uses less password iterations", "N");
+    opts.optflag("r", "read-only", "don't take the lock or update access times on copy and show");
+    opts.optopt("t", "time", "show times in local time zone (default), UTC or as ISO 8601", "local|utc|iso");
+    opts.optopt("", "entry", "generate password for the entry matching REGEXP", "REGEXP");
+    opts.optopt("", "policy", "generate password following the named policy", "NAME");
+    opts.optopt("", "chars", "character classes of the generated password", "CLASSES");
+    opts.optopt("", "length", "length of the generated password", "N");
+    opts.optopt("", "symbols", "symbols allowed in the generated password", "CHARS");
+    opts.optopt("", "words", "generate passphrase of N words", "N");
+    opts.optopt("", "separator", "separator between passphrase words (default -)", "S");
+    opts.optflag("", "capitalize", "capitalize passphrase words");
+    opts.optflag("", "digit", "add a digit to a random passphrase word");
+    opts.optopt("", "wordlist", "wordlist for passphrases", "PATH");
+    opts.optflag("", "copy", "copy the one-time password to clipboard instead of printing it");
+    opts.optopt("", "import", "store the two-factor key of an otpauth:// URI in the entry", "URI");
     opts.optflag("h", "help", "print this help menu");
 
     let args: Vec<String> = env::args().collect();
@@ -312,6 +1128,68 @@ fn main() {
 
     unsafe {
         STDIN_PASSWORD = matches.opt_present("S");
+        READ_ONLY = matches.opt_present("r");
+    }
+
+    match matches.opt_str("b") {
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) => unsafe { BACKUPS = n },
+            Err(_) => {
+                eprintln!("Invalid number of backups '{}'", n);
+                return;
+            },
+        },
+        None => (),
+    }
+
+    match matches.opt_str("i") {
+        Some(n) => match n.parse::<u32>() {
+            Ok(n) if n >= keychain::MIN_ITER => unsafe { ITER = n },
+            _ => {
+                eprintln!("Invalid number of iterations '{}', at least {} are required", n, keychain::MIN_ITER);
+                return;
+            },
+        },
+        None => (),
+    }
+
+    match matches.opt_str("calibrate") {
+        Some(ms) => match ms.parse::<u64>() {
+            Ok(ms) => {
+                let n = keychain::calibrate(ms);
+                println!("Using {} password iterations", n);
+                unsafe { ITER = n };
+            },
+            Err(_) => {
+                eprintln!("Invalid calibration time '{}'", ms);
+                return;
+            },
+        },
+        None => (),
+    }
+
+    match matches.opt_str("min-iter") {
+        Some(n) => match n.parse::<u32>() {
+            Ok(n) => unsafe { ITER_FLOOR = n },
+            Err(_) => {
+                eprintln!("Invalid number of iterations '{}'", n);
+                return;
+            },
+        },
+        None => (),
+    }
+
+    match matches.opt_str("t") {
+        Some(t) => match t.as_ref() {
+            "local" => unsafe { TIME_FORMAT = TimeFormat::Local },
+            "utc" => unsafe { TIME_FORMAT = TimeFormat::Utc },
+            "iso" => unsafe { TIME_FORMAT = TimeFormat::Iso },
+            _ => {
+                eprintln!("Invalid time format '{}'", t);
+                return;
+            },
+        },
+        None => (),
     }
 
     let db_path = match matches.opt_str("p") {
@@ -324,7 +1202,7 @@ fn main() {
         },
     };
 
-    if !run_op(&db_path, &matches.free) {
+    if !run_op(&db_path, &matches) {
         print_usage(&exe, opts);
     }
 }
@@ -0,0 +1,223 @@
+// One-time passwords (RFC 4226 HOTP, RFC 6238 TOTP) from the two-factor key
+// of an entry (field 0x1b). The rest of the parameters live in the TOTP
+// config (0x21, the low 2 bits select the hash: 0 is SHA-1, the only one
+// Password Safe writes, 1 SHA-256 and 2 SHA-512), length (0x22), time
+// step (0x23) and start time (0x24) fields, which all have defaults.
+
+use std::fmt;
+
+use crypto;
+use error::Error;
+use secret;
+
+pub use crypto::Algorithm;
+
+/// HOTP code for the counter, `digits` long with leading zeros.
+pub fn hotp(key: &[u8], algorithm: Algorithm, counter: u64, digits: u8) -> String {
+    let mut counter_be = [0; 8];
+    for i in 0..8 {
+        counter_be[i] = (counter >> (56 - 8 * i)) as u8;
+    }
+
+    let mut mac = crypto::hmac(algorithm, key, &counter_be);
+
+    // dynamic truncation
+    let off = (mac[mac.len() - 1] & 0xf) as usize;
+    let bin = ((mac[off] as u64 & 0x7f) << 24)
+        | ((mac[off + 1] as u64) << 16)
+        | ((mac[off + 2] as u64) << 8)
+        | (mac[off + 3] as u64);
+    secret::wipe(&mut mac);
+
+    // bin has 31 bits, more than 10 digits don't change it
+    let code = match 10u64.checked_pow(digits as u32) {
+        Some(m) => bin % m,
+        None => bin,
+    };
+    return format!("{:0width$}", code, width = digits as usize);
+}
+
+/// TOTP parameters of an entry.
+#[derive(Clone,PartialEq,Eq)]
+pub struct Totp {
+    pub key: Vec<u8>,
+    pub algorithm: Algorithm,
+    /// length of the codes, 6 by default
+    pub digits: u8,
+    // seconds every code is valid for, never 0
+    period: u8,
+    /// time the first period starts at, the epoch by default
+    pub start: u64,
+}
+
+impl Totp {
+    pub fn new(key: Vec<u8>) -> Totp {
+        secret::lock(&key);
+        return Totp{key, algorithm: Algorithm::Sha1, digits: 6, period: 30, start: 0};
+    }
+
+    /// Seconds every code is valid for, 30 by default.
+    pub fn period(&self) -> u8 { self.period }
+
+    /// Sets the seconds every code is valid for, returns false (and keeps
+    /// the old value) for 0.
+    pub fn set_period(&mut self, period: u8) -> bool {
+        if period == 0 {
+            return false;
+        }
+        self.period = period;
+        return true;
+    }
+
+    /// Code valid at the given time (seconds since the epoch).
+    pub fn code(&self, now: u64) -> String {
+        let counter = now.saturating_sub(self.start) / self.period as u64;
+        return hotp(&self.key, self.algorithm, counter, self.digits);
+    }
+
+    /// Seconds the code of the given time stays valid for.
+    pub fn remaining(&self, now: u64) -> u64 {
+        let period = self.period as u64;
+        return period - now.saturating_sub(self.start) % period;
+    }
+
+    /// Parses an otpauth://totp/ URI as exported by authenticator apps,
+    /// the label and issuer are ignored.
+    pub fn from_uri(uri: &str) -> Result<Totp, Error> {
+        let invalid = |e: &str| Error::InvalidOtpUri(e.to_string());
+
+        let rest = match uri.find("://") {
+            Some(n) if uri[..n].eq_ignore_ascii_case("otpauth") => &uri[n + 3..],
+            _ => return Err(invalid("not an otpauth:// URI")),
+        };
+
+        let (path, query) = match rest.find('?') {
+            Some(n) => (&rest[..n], &rest[n + 1..]),
+            None => (rest, ""),
+        };
+        if !path.to_lowercase().starts_with("totp/") {
+            return Err(invalid("only time based (totp) codes are supported"));
+        }
+
+        let mut totp = Totp::new(Vec::new());
+        let mut has_secret = false;
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            let (k, v) = match param.find('=') {
+                Some(n) => (&param[..n], percent_decode(&param[n + 1..]).ok_or_else(|| invalid("bad percent encoding"))?),
+                None => (param, String::new()),
+            };
+
+            match k.to_lowercase().as_ref() {
+                "secret" => {
+                    totp.key = base32_decode(&v).ok_or_else(|| invalid("secret is not base32"))?;
+                    secret::lock(&totp.key);
+                    has_secret = true;
+                },
+                "algorithm" => totp.algorithm = match v.to_uppercase().as_ref() {
+                    "SHA1" => Algorithm::Sha1,
+                    "SHA256" => Algorithm::Sha256,
+                    "SHA512" => Algorithm::Sha512,
+                    _ => return Err(invalid("unknown algorithm")),
+                },
+                "digits" => totp.digits = match v.parse::<u8>() {
+                    Ok(n) if n >= 1 && n <= 10 => n,
+                    _ => return Err(invalid("digits must be between 1 and 10")),
+                },
+                "period" => match v.parse::<u8>() {
+                    Ok(n) if totp.set_period(n) => (),
+                    _ => return Err(invalid("period must be between 1 and 255 seconds")),
+                },
+                _ => (),
+            }
+        }
+
+        if !has_secret || totp.key.is_empty() {
+            return Err(invalid("secret is missing"));
+        }
+        return Ok(totp);
+    }
+
+    /// Value of the TOTP config field.
+    pub fn config(&self) -> u8 {
+        match self.algorithm {
+            Algorithm::Sha1 => return 0,
+            Algorithm::Sha256 => return 1,
+            Algorithm::Sha512 => return 2,
+        }
+    }
+
+    /// Algorithm of the TOTP config field, None for unknown ones.
+    pub fn algorithm_from_config(config: u8) -> Option<Algorithm> {
+        match config & 0x3 {
+            0 => return Some(Algorithm::Sha1),
+            1 => return Some(Algorithm::Sha256),
+            2 => return Some(Algorithm::Sha512),
+            _ => return None,
+        }
+    }
+}
+
+impl Drop for Totp {
+    fn drop(&mut self) {
+        secret::wipe(&mut self.key);
+    }
+}
+
+impl fmt::Debug for Totp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Totp {{ algorithm: {:?}, digits: {}, period: {}, start: {} }}",
+               self.algorithm, self.digits, self.period, self.start)
+    }
+}
+
+// RFC 4648 base32, case insensitive, padding and spaces are ignored.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut v = Vec::with_capacity(s.len() * 5 / 8);
+    let mut bits: u32 = 0;
+    let mut n = 0;
+
+    for c in s.chars().filter(|&c| c != '=' && c != ' ') {
+        let d = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => return None,
+        };
+
+        bits = (bits << 5) | d;
+        n += 5;
+        if n >= 8 {
+            n -= 8;
+            v.push((bits >> n) as u8);
+            bits &= (1 << n) - 1;
+        }
+    }
+    return Some(v);
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let b = s.as_bytes();
+    let mut v = Vec::with_capacity(b.len());
+
+    let mut i = 0;
+    while i < b.len() {
+        match b[i] {
+            b'%' => {
+                let hex = s.get(i + 1..i + 3)?;
+                if !hex.chars().all(|c| c.is_digit(16)) {
+                    return None;
+                }
+                v.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            },
+            b'+' => {
+                v.push(b' ');
+                i += 1;
+            },
+            c => {
+                v.push(c);
+                i += 1;
+            },
+        }
+    }
+    return String::from_utf8(v).ok();
+}
@@ -0,0 +1,103 @@
+// Diceware style passphrases: words picked at random from a list such as
+// the EFF large wordlist (https://www.eff.org/dice), where every line is
+// the five dice rolls that select the word followed by the word itself.
+
+use rand::{OsRng, Rng};
+
+use error::Error;
+use secret::SecretString;
+
+/// Words to pick passphrases from.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Wordlist {
+    words: Vec<String>,
+}
+
+impl Wordlist {
+    /// Parses a list with one word per line, optionally preceded by the
+    /// dice rolls as in the EFF lists. Returns None if there are less than
+    /// two distinct words.
+    pub fn parse(s: &str) -> Option<Wordlist> {
+        let mut words: Vec<String> = s.lines().filter_map(|l| l.split_whitespace().last()).map(String::from).collect();
+        words.sort();
+        words.dedup();
+
+        if words.len() < 2 {
+            return None;
+        }
+        return Some(Wordlist{words});
+    }
+
+    pub fn len(&self) -> usize { self.words.len() }
+
+    pub fn is_empty(&self) -> bool { self.words.is_empty() }
+}
+
+/// How to put passphrases together.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Passphrase {
+    pub words: usize,
+    pub separator: String,
+    /// capitalize the first letter of every word
+    pub capitalize: bool,
+    /// append a random digit to a random word
+    pub digit: bool,
+}
+
+// Six words from the EFF list give about 77 bits.
+impl Default for Passphrase {
+    fn default() -> Passphrase {
+        return Passphrase{words: 6, separator: "-".to_string(), capitalize: false, digit: false};
+    }
+}
+
+impl Passphrase {
+    pub fn generate(&self, list: &Wordlist) -> Result<SecretString, Error> {
+        if self.words == 0 {
+            return Err(Error::InvalidPolicy("passphrase has no words".to_string()));
+        }
+
+        let mut rng = OsRng::new()?;
+        let digit = if self.digit {
+            Some((rng.gen_range(0, self.words), rng.gen_range(0, 10)))
+        } else {
+            None
+        };
+
+        // no reallocations, they would leave copies of the passphrase behind
+        let longest = list.words.iter().map(|w| w.len()).max().unwrap_or(0);
+        let mut s = String::with_capacity(self.words * (longest + self.separator.len() + 1));
+
+        for i in 0..self.words {
+            if i > 0 {
+                s.push_str(&self.separator);
+            }
+
+            let word = &list.words[rng.gen_range(0, list.words.len())];
+            for (j, c) in word.chars().enumerate() {
+                if j == 0 && self.capitalize {
+                    s.extend(c.to_uppercase());
+                } else {
+                    s.push(c);
+                }
+            }
+
+            match digit {
+                Some((n, d)) if n == i => s.push_str(&d.to_string()),
+                _ => (),
+            }
+        }
+
+        return Ok(SecretString::new(s));
+    }
+
+    /// Estimated entropy in bits of the passphrases `generate` makes with
+    /// the list, capitalization doesn't add any.
+    pub fn entropy(&self, list: &Wordlist) -> f64 {
+        let mut bits = self.words as f64 * (list.len() as f64).log2();
+        if self.digit && self.words > 0 {
+            bits += 10f64.log2() + (self.words as f64).log2();
+        }
+        return bits;
+    }
+}
@@ -0,0 +1,453 @@
+// Password policy (entry field 0x10) is stored as text: "ffffnnnllluuudddsss"
+// where ffff are the flags (4 hex digits), followed by the length and the
+// minimum number of lowercase, uppercase, digits and symbols (3 hex digits
+// each). The symbols to use can be overridden per entry (field 0x16).
+//
+// Named policies (header field 0x10) are stored as text too: number of
+// policies (2 hex digits), then for each one the length of the name (2 hex
+// digits), the name, the policy as above, the length of its symbols (2 hex
+// digits) and the symbols. Entries refer to them by name (field 0x18).
+
+use std::fmt;
+use std::ptr;
+use rand::{OsRng, Rng};
+
+use error::Error;
+use history::hex;
+use item::Data;
+use secret::SecretString;
+
+const USE_LOWERCASE: u16 = 0x8000;
+const USE_UPPERCASE: u16 = 0x4000;
+const USE_DIGITS: u16 = 0x2000;
+const USE_SYMBOLS: u16 = 0x1000;
+const USE_HEX_DIGITS: u16 = 0x0800;
+const USE_EASY_VISION: u16 = 0x0400;
+const MAKE_PRONOUNCEABLE: u16 = 0x0200;
+
+// Same character sets as Password Safe, easy vision ones leave out
+// characters that are easy to confuse (l and 1, O and 0, ...).
+const LOWERCASE: &str = "abcdefghijklmnopqrstuvwxyz";
+const UPPERCASE: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGITS: &str = "0123456789";
+const HEX_DIGITS: &str = "0123456789abcdef";
+/// Symbols used when the entry doesn't have its own.
+pub const SYMBOLS: &str = "+-=_@#$%^&;:,.<>/~\\[](){}?!|*";
+const EASY_LOWERCASE: &str = "abcdefghijkmnopqrstuvwxyz";
+const EASY_UPPERCASE: &str = "ABCDEFGHJKLMNPQRTUVWXY";
+const EASY_DIGITS: &str = "346789";
+/// Symbols used with easy vision when the entry doesn't have its own.
+pub const EASY_SYMBOLS: &str = "+-=_@#$%^&<>/~\\?*";
+/// Symbols used in pronounceable passwords when the entry doesn't have its
+/// own.
+pub const PRONOUNCEABLE_SYMBOLS: &str = "@&(#!|$+";
+// pronounceable passwords alternate between these
+const CONSONANTS: &str = "bcdfghjklmnprstvz";
+const VOWELS: &str = "aeiou";
+
+/// Rules for generating passwords of an entry.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct PasswordPolicy {
+    pub lowercase: bool,
+    pub uppercase: bool,
+    pub digits: bool,
+    pub symbols: bool,
+    /// only hex digits, other classes and minimums are ignored
+    pub hex: bool,
+    /// leave out characters that are easy to confuse, ignored for
+    /// pronounceable passwords
+    pub easy_vision: bool,
+    /// alternate consonants and vowels, with at least one (or the minimum
+    /// number of) uppercase letters, digits and symbols mixed in if enabled
+    pub pronounceable: bool,
+    pub length: usize,
+    pub min_lowercase: usize,
+    pub min_uppercase: usize,
+    pub min_digits: usize,
+    pub min_symbols: usize,
+}
+
+// Password Safe defaults.
+impl Default for PasswordPolicy {
+    fn default() -> PasswordPolicy {
+        return PasswordPolicy{
+            lowercase: true,
+            uppercase: true,
+            digits: true,
+            symbols: true,
+            hex: false,
+            easy_vision: false,
+            pronounceable: false,
+            length: 12,
+            min_lowercase: 1,
+            min_uppercase: 1,
+            min_digits: 1,
+            min_symbols: 1,
+        };
+    }
+}
+
+impl PasswordPolicy {
+    /// Parses the Password Safe representation, returns None if it's
+    /// malformed.
+    pub fn parse(s: &str) -> Option<PasswordPolicy> {
+        let mut c = s.chars();
+        let p = PasswordPolicy::parse_from(&mut c)?;
+
+        if c.next().is_some() {
+            return None;
+        }
+        return Some(p);
+    }
+
+    fn parse_from(c: &mut Iterator<Item=char>) -> Option<PasswordPolicy> {
+        let flags = hex(c, 4)? as u16;
+        let length = hex(c, 3)? as usize;
+        let min_lowercase = hex(c, 3)? as usize;
+        let min_uppercase = hex(c, 3)? as usize;
+        let min_digits = hex(c, 3)? as usize;
+        let min_symbols = hex(c, 3)? as usize;
+
+        return Some(PasswordPolicy{
+            lowercase: flags & USE_LOWERCASE != 0,
+            uppercase: flags & USE_UPPERCASE != 0,
+            digits: flags & USE_DIGITS != 0,
+            symbols: flags & USE_SYMBOLS != 0,
+            hex: flags & USE_HEX_DIGITS != 0,
+            easy_vision: flags & USE_EASY_VISION != 0,
+            pronounceable: flags & MAKE_PRONOUNCEABLE != 0,
+            length,
+            min_lowercase,
+            min_uppercase,
+            min_digits,
+            min_symbols,
+        });
+    }
+
+    /// Field contents in the Password Safe representation.
+    pub fn to_data(&self) -> Data {
+        return Data::Text(self.encode());
+    }
+
+    fn encode(&self) -> String {
+        let mut flags = 0;
+        for &(on, flag) in [
+            (self.lowercase, USE_LOWERCASE),
+            (self.uppercase, USE_UPPERCASE),
+            (self.digits, USE_DIGITS),
+            (self.symbols, USE_SYMBOLS),
+            (self.hex, USE_HEX_DIGITS),
+            (self.easy_vision, USE_EASY_VISION),
+            (self.pronounceable, MAKE_PRONOUNCEABLE),
+        ].iter() {
+            if on {
+                flags |= flag;
+            }
+        }
+
+        // the format has no room for more than 3 hex digits
+        let n = |v: usize| if v > 0xfff { 0xfff } else { v };
+        return format!("{:04x}{:03x}{:03x}{:03x}{:03x}{:03x}", flags, n(self.length),
+                       n(self.min_lowercase), n(self.min_uppercase), n(self.min_digits), n(self.min_symbols));
+    }
+
+    /// Generates a random password that follows the policy. `symbols`
+    /// overrides the default set of symbols (e.g. from the entry).
+    pub fn generate(&self, symbols: Option<&str>) -> Result<SecretString, Error> {
+        if self.length == 0 {
+            return Err(Error::InvalidPolicy("password length is zero".to_string()));
+        }
+
+        let mut rng = OsRng::new()?;
+        let mut v: Vec<char> = Vec::with_capacity(self.length);
+
+        if self.hex {
+            let pool: Vec<char> = HEX_DIGITS.chars().collect();
+            for _ in 0..self.length {
+                v.push(pool[rng.gen_range(0, pool.len())]);
+            }
+            return Ok(into_secret(v));
+        }
+
+        if self.pronounceable {
+            self.pronounceable(symbols, &mut rng, &mut v)?;
+            return Ok(into_secret(v));
+        }
+
+        let classes = self.classes(symbols);
+        if classes.is_empty() {
+            return Err(Error::InvalidPolicy("no characters to choose from".to_string()));
+        }
+        if classes.iter().map(|c| c.1).sum::<usize>() > self.length {
+            return Err(Error::InvalidPolicy("minimum counts exceed password length".to_string()));
+        }
+
+        // required characters of every class first, the rest from all of
+        // them, then shuffled so the required ones aren't at the start
+        let mut pool = Vec::new();
+        for &(ref set, min) in classes.iter() {
+            for _ in 0..min {
+                v.push(set[rng.gen_range(0, set.len())]);
+            }
+            pool.extend_from_slice(set);
+        }
+        while v.len() < self.length {
+            v.push(pool[rng.gen_range(0, pool.len())]);
+        }
+        rng.shuffle(&mut v);
+
+        return Ok(into_secret(v));
+    }
+
+    fn pronounceable(&self, symbols: Option<&str>, rng: &mut OsRng, v: &mut Vec<char>) -> Result<(), Error> {
+        if !self.lowercase && !self.uppercase {
+            return Err(Error::InvalidPolicy("pronounceable passwords need letters".to_string()));
+        }
+
+        let (upper, digits, symbols_n) = self.mixed_in();
+        if upper + digits + symbols_n > self.length {
+            return Err(Error::InvalidPolicy("minimum counts exceed password length".to_string()));
+        }
+
+        let consonants = charset(CONSONANTS);
+        let vowels = charset(VOWELS);
+        for i in 0..self.length {
+            let set = if i % 2 == 0 { &consonants } else { &vowels };
+            let c = set[rng.gen_range(0, set.len())];
+            v.push(if self.lowercase { c } else { c.to_ascii_uppercase() });
+        }
+
+        // distinct random positions for the mixed in characters
+        let mut pos: Vec<usize> = (0..self.length).collect();
+        rng.shuffle(&mut pos);
+        let mut pos = pos.into_iter();
+
+        for p in pos.by_ref().take(upper) {
+            v[p] = v[p].to_ascii_uppercase();
+        }
+
+        let set = charset(DIGITS);
+        for p in pos.by_ref().take(digits) {
+            v[p] = set[rng.gen_range(0, set.len())];
+        }
+
+        let set = charset(pronounceable_symbols(symbols));
+        for p in pos.by_ref().take(symbols_n) {
+            v[p] = set[rng.gen_range(0, set.len())];
+        }
+
+        return Ok(());
+    }
+
+    // Number of uppercase letters, digits and symbols mixed into
+    // pronounceable passwords, uppercase ones only if there are lowercase
+    // ones (otherwise all of them are uppercase).
+    fn mixed_in(&self) -> (usize, usize, usize) {
+        let n = |on: bool, min: usize| if !on { 0 } else if min > 0 { min } else { 1 };
+        let upper = if self.lowercase { n(self.uppercase, self.min_uppercase) } else { 0 };
+        return (upper, n(self.digits, self.min_digits), n(self.symbols, self.min_symbols));
+    }
+
+    /// Estimated entropy in bits of the passwords `generate` makes with the
+    /// same arguments.
+    pub fn entropy(&self, symbols: Option<&str>) -> f64 {
+        let length = self.length as f64;
+
+        if self.hex {
+            return length * 4.0;
+        }
+
+        if self.pronounceable {
+            let (upper, digits, symbols_n) = self.mixed_in();
+            if upper + digits + symbols_n > self.length {
+                return 0.0;
+            }
+
+            // a consonant and a vowel for every two letters
+            let letter = ((CONSONANTS.len() * VOWELS.len()) as f64).log2() / 2.0;
+            let letters = (self.length - digits - symbols_n) as f64;
+            let symbols = charset(pronounceable_symbols(symbols)).len() as f64;
+
+            return letters * letter
+                + digits as f64 * 10f64.log2()
+                + symbols_n as f64 * symbols.log2()
+                + log2_choose(self.length, upper)
+                + log2_choose(self.length - upper, digits)
+                + log2_choose(self.length - upper - digits, symbols_n);
+        }
+
+        let pool: usize = self.classes(symbols).iter().map(|c| c.0.len()).sum();
+        if pool == 0 {
+            return 0.0;
+        }
+        return length * (pool as f64).log2();
+    }
+
+    // Enabled character classes with their minimum counts.
+    fn classes(&self, symbols: Option<&str>) -> Vec<(Vec<char>, usize)> {
+        let (lowercase, uppercase, digits, default_symbols) = if self.easy_vision {
+            (EASY_LOWERCASE, EASY_UPPERCASE, EASY_DIGITS, EASY_SYMBOLS)
+        } else {
+            (LOWERCASE, UPPERCASE, DIGITS, SYMBOLS)
+        };
+        let symbols = match symbols {
+            Some(s) if !s.is_empty() => s,
+            _ => default_symbols,
+        };
+
+        let mut classes = Vec::new();
+        for &(on, set, min) in [
+            (self.lowercase, lowercase, self.min_lowercase),
+            (self.uppercase, uppercase, self.min_uppercase),
+            (self.digits, digits, self.min_digits),
+            (self.symbols, symbols, self.min_symbols),
+        ].iter() {
+            if on {
+                classes.push((charset(set), min));
+            }
+        }
+        return classes;
+    }
+}
+
+// E.g. "length 14: lowercase (at least 1), digits (at least 2)".
+impl fmt::Display for PasswordPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "length {}: ", self.length)?;
+
+        let mut v = Vec::new();
+        if self.hex {
+            v.push("hex digits".to_string());
+        } else {
+            for &(on, name, min) in [
+                (self.lowercase, "lowercase", self.min_lowercase),
+                (self.uppercase, "uppercase", self.min_uppercase),
+                (self.digits, "digits", self.min_digits),
+                (self.symbols, "symbols", self.min_symbols),
+            ].iter() {
+                if on {
+                    v.push(format!("{} (at least {})", name, min));
+                }
+            }
+        }
+        if self.easy_vision {
+            v.push("easy vision".to_string());
+        }
+        if self.pronounceable {
+            v.push("pronounceable".to_string());
+        }
+
+        write!(f, "{}", v.join(", "))
+    }
+}
+
+/// Password policy stored in the database header under a name.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct NamedPolicy {
+    pub name: String,
+    pub policy: PasswordPolicy,
+    /// symbols to use instead of the default ones, empty for the defaults
+    pub symbols: String,
+}
+
+/// Named password policies of a database.
+#[derive(Debug,Clone,PartialEq,Eq,Default)]
+pub struct NamedPolicies {
+    pub policies: Vec<NamedPolicy>,
+}
+
+impl NamedPolicies {
+    /// Parses the Password Safe representation, returns None if it's
+    /// malformed.
+    pub fn parse(s: &str) -> Option<NamedPolicies> {
+        let mut c = s.chars();
+
+        let count = hex(&mut c, 2)?;
+        let mut policies = Vec::new();
+        for _ in 0..count {
+            let len = hex(&mut c, 2)? as usize;
+            let name = text(&mut c, len)?;
+            let policy = PasswordPolicy::parse_from(&mut c)?;
+            let len = hex(&mut c, 2)? as usize;
+            let symbols = text(&mut c, len)?;
+            policies.push(NamedPolicy{name, policy, symbols});
+        }
+
+        if c.next().is_some() {
+            return None;
+        }
+        return Some(NamedPolicies{policies});
+    }
+
+    /// Field contents in the Password Safe representation, names and
+    /// symbols longer than 255 characters are truncated.
+    pub fn to_data(&self) -> Data {
+        let n = if self.policies.len() > 0xff { 0xff } else { self.policies.len() };
+
+        let mut s = format!("{:02x}", n);
+        for p in self.policies.iter().take(n) {
+            let name: String = p.name.chars().take(0xff).collect();
+            let symbols: String = p.symbols.chars().take(0xff).collect();
+            s.push_str(&format!("{:02x}{}{}{:02x}{}", name.chars().count(), name, p.policy.encode(),
+                                symbols.chars().count(), symbols));
+        }
+        return Data::Text(s);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&NamedPolicy> {
+        return self.policies.iter().find(|p| p.name == name);
+    }
+
+    /// Adds a policy or replaces the one with the same name.
+    pub fn insert(&mut self, p: NamedPolicy) {
+        match self.policies.iter().position(|v| v.name == p.name) {
+            Some(i) => self.policies[i] = p,
+            None => self.policies.push(p),
+        }
+    }
+
+    /// Returns false if there is no policy with the given name.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.policies.len();
+        self.policies.retain(|p| p.name != name);
+        return self.policies.len() != len;
+    }
+}
+
+// Characters of the set, duplicates would make some of them more likely.
+fn charset(s: &str) -> Vec<char> {
+    let mut v: Vec<char> = s.chars().collect();
+    v.sort();
+    v.dedup();
+    return v;
+}
+
+fn pronounceable_symbols(symbols: Option<&str>) -> &str {
+    match symbols {
+        Some(s) if !s.is_empty() => return s,
+        _ => return PRONOUNCEABLE_SYMBOLS,
+    }
+}
+
+// Bits needed to pick k out of n.
+fn log2_choose(n: usize, k: usize) -> f64 {
+    return (0..k).map(|i| ((n - i) as f64).log2() - ((i + 1) as f64).log2()).sum();
+}
+
+fn text(c: &mut Iterator<Item=char>, len: usize) -> Option<String> {
+    let mut s = String::new();
+    for _ in 0..len {
+        s.push(c.next()?);
+    }
+    return Some(s);
+}
+
+// Password from the generated characters, which are wiped.
+fn into_secret(mut v: Vec<char>) -> SecretString {
+    let mut s = String::with_capacity(v.iter().map(|c| c.len_utf8()).sum());
+    s.extend(v.iter());
+    for c in v.iter_mut() {
+        unsafe { ptr::write_volatile(c, '\0') };
+    }
+    return SecretString::new(s);
+}
@@ -0,0 +1,89 @@
+// Alias and shortcut entries are marked by their password: an alias stores
+// "[[uuid]]" and uses the password of the base entry, a shortcut stores
+// "[~uuid~]" and uses all fields of the base entry except its own group,
+// title, username, UUID and times. The UUID is 32 hex digits, Password
+// Safe also accepts "[[group:title:user]]", "[[group:title]]" and
+// "[[title]]" typed in by hand (and the same for shortcuts).
+
+use std::fmt;
+
+use item::{Kind, Uuid};
+
+/// Entry a reference points to.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Target {
+    Uuid(Uuid),
+    /// group, title and username, empty ones match missing fields
+    Name(String, String, String),
+}
+
+/// What an alias or shortcut entry refers to.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Reference {
+    Alias(Target),
+    Shortcut(Target),
+}
+
+impl Reference {
+    /// Parses the password of an entry, None if it's not a reference.
+    pub fn parse(password: &str) -> Option<Reference> {
+        if password.len() <= 4 {
+            return None;
+        }
+
+        // both ends are ASCII, so slicing them off is safe
+        let inner = || &password[2..password.len() - 2];
+        if password.starts_with("[[") && password.ends_with("]]") {
+            return Some(Reference::Alias(parse_target(inner())));
+        }
+        if password.starts_with("[~") && password.ends_with("~]") {
+            return Some(Reference::Shortcut(parse_target(inner())));
+        }
+        return None;
+    }
+
+    pub fn target(&self) -> &Target {
+        match *self {
+            Reference::Alias(ref t) => return t,
+            Reference::Shortcut(ref t) => return t,
+        }
+    }
+
+    /// Whether the field of the given kind comes from the target.
+    pub fn follows(&self, kind: Kind) -> bool {
+        match *self {
+            Reference::Alias(_) => return kind == Kind::Password,
+            Reference::Shortcut(_) => match kind {
+                Kind::UUID | Kind::Group | Kind::Title | Kind::Username |
+                Kind::CreateTime | Kind::AccessTime | Kind::ModifyTime => return false,
+                _ => return true,
+            },
+        }
+    }
+}
+
+fn parse_target(s: &str) -> Target {
+    match Uuid::parse(s) {
+        Some(u) if !s.contains('-') => return Target::Uuid(u),
+        _ => (),
+    }
+
+    let v: Vec<&str> = s.splitn(3, ':').collect();
+    match v.len() {
+        1 => return Target::Name(String::new(), v[0].to_string(), String::new()),
+        2 => return Target::Name(v[0].to_string(), v[1].to_string(), String::new()),
+        _ => return Target::Name(v[0].to_string(), v[1].to_string(), v[2].to_string()),
+    }
+}
+
+// Password of the alias or shortcut entry.
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Reference::Alias(Target::Uuid(ref u)) => write!(f, "[[{}]]", u.to_hex()),
+            Reference::Alias(Target::Name(ref g, ref t, ref u)) => write!(f, "[[{}:{}:{}]]", g, t, u),
+            Reference::Shortcut(Target::Uuid(ref u)) => write!(f, "[~{}~]", u.to_hex()),
+            Reference::Shortcut(Target::Name(ref g, ref t, ref u)) => write!(f, "[~{}:{}:{}~]", g, t, u),
+        }
+    }
+}
@@ -0,0 +1,298 @@
+// Best effort protection of secrets in memory: buffers are wiped when
+// dropped and locked into RAM so they don't end up in swap, core dumps
+// and ptrace attach are disabled while any database is unlocked and
+// sensitive fields are kept encrypted with a random per-process key.
+//
+// Pages are never unlocked since other secrets may live on the same page.
+
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{compiler_fence, Ordering};
+use libc;
+use rand::{OsRng, Rng};
+
+use crypto;
+
+/// Overwrites the buffer with zeros in a way the compiler can't optimize out.
+pub fn wipe(b: &mut [u8]) {
+    for x in b.iter_mut() {
+        unsafe { ptr::write_volatile(x, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Locks pages of the buffer into RAM, failures (e.g. RLIMIT_MEMLOCK)
+/// are ignored.
+#[cfg(unix)]
+pub fn lock(b: &[u8]) {
+    if !b.is_empty() {
+        unsafe { libc::mlock(b.as_ptr() as *const libc::c_void, b.len()) };
+    }
+}
+
+#[cfg(not(unix))]
+pub fn lock(_b: &[u8]) {
+}
+
+/// 256-bit key (stretched password, K, L) locked in RAM and wiped on drop.
+pub struct SecretKey {
+    key: Box<[u8; 32]>,
+}
+
+impl SecretKey {
+    pub fn new() -> SecretKey {
+        let key = Box::new([0; 32]);
+        lock(&key[..]);
+        return SecretKey{key};
+    }
+}
+
+impl Deref for SecretKey {
+    type Target = [u8; 32];
+    fn deref(&self) -> &[u8; 32] { &self.key }
+}
+
+impl DerefMut for SecretKey {
+    fn deref_mut(&mut self) -> &mut [u8; 32] { &mut self.key }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        wipe(&mut self.key[..]);
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretKey(..)")
+    }
+}
+
+/// String (e.g. master password) locked in RAM and wiped on drop. It takes
+/// over the buffer of the original string, so no copies are left behind.
+pub struct SecretString {
+    s: String,
+}
+
+impl SecretString {
+    pub fn new(s: String) -> SecretString {
+        lock(s.as_bytes());
+        return SecretString{s};
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(s: String) -> SecretString { SecretString::new(s) }
+}
+
+impl Deref for SecretString {
+    type Target = str;
+    fn deref(&self) -> &str { &self.s }
+}
+
+impl Clone for SecretString {
+    fn clone(&self) -> SecretString { SecretString::new(self.s.clone()) }
+}
+
+impl PartialEq for SecretString {
+    fn eq(&self, other: &SecretString) -> bool { crypto::ct_eq(self.s.as_bytes(), other.s.as_bytes()) }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        unsafe { wipe(self.s.as_bytes_mut()) };
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretString(..)")
+    }
+}
+
+lazy_static! {
+    static ref SESSION: SecretKey = {
+        crypto::init();
+        let mut key = SecretKey::new();
+        OsRng::new().expect("Can't initialize random number generator").fill_bytes(&mut key[..]);
+        key
+    };
+}
+
+/// Bytes encrypted in memory with the session key, see `open`.
+#[derive(Clone)]
+pub struct Sealed {
+    len: usize,
+    iv: [u8; 16],
+    data: Vec<u8>,
+}
+
+impl Sealed {
+    pub fn new(b: &[u8]) -> Sealed {
+        let mut iv: [u8; 16] = [0; 16];
+        OsRng::new().expect("Can't initialize random number generator").fill_bytes(&mut iv);
+
+        // zero padded to the block size, at least one block
+        let mut data = Vec::with_capacity(b.len() + 16 - b.len() % 16);
+        data.extend_from_slice(b);
+        data.resize(b.len() + 16 - b.len() % 16, 0);
+        crypto::encrypt_inplace(&mut data, &SESSION[..], &iv).expect("Can't seal data");
+
+        return Sealed{len: b.len(), iv, data};
+    }
+
+    /// Calls `f` with the decrypted bytes, which are wiped afterwards.
+    pub fn open<T, F: FnOnce(&[u8]) -> T>(&self, f: F) -> T {
+        let mut b = self.data.clone();
+        lock(&b);
+        crypto::decrypt_inplace(&mut b, &SESSION[..], &self.iv).expect("Can't open sealed data");
+        let r = f(&b[..self.len]);
+        wipe(&mut b);
+        return r;
+    }
+
+    pub fn len(&self) -> usize { self.len }
+
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+}
+
+impl PartialEq for Sealed {
+    fn eq(&self, other: &Sealed) -> bool {
+        self.open(|a| other.open(|b| crypto::ct_eq(a, b)))
+    }
+}
+
+impl Eq for Sealed {}
+
+// Only the length, so equal contents give equal hashes without
+// revealing anything else.
+impl Hash for Sealed {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len.hash(state);
+    }
+}
+
+impl fmt::Debug for Sealed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Sealed(..)")
+    }
+}
+
+#[cfg(unix)]
+type CoreLimit = libc::rlimit;
+
+#[cfg(not(unix))]
+type CoreLimit = ();
+
+// State to restore when the last guard is dropped.
+struct Unlocked {
+    count: usize,
+    core: Option<CoreLimit>,
+    dumpable: bool,
+}
+
+lazy_static! {
+    static ref UNLOCKED: Mutex<Unlocked> = Mutex::new(Unlocked{count: 0, core: None, dumpable: false});
+}
+
+/// Disables core dumps and ptrace attach for as long as at least one
+/// guard is alive, held by every unlocked database.
+#[derive(Debug)]
+pub struct Guard {
+    _private: (),
+}
+
+impl Guard {
+    pub fn new() -> Guard {
+        let mut u = match UNLOCKED.lock() {
+            Ok(u) => u,
+            Err(e) => e.into_inner(),
+        };
+
+        if u.count == 0 {
+            let (core, dumpable) = disable_dumps();
+            u.core = core;
+            u.dumpable = dumpable;
+        }
+        u.count += 1;
+
+        return Guard{_private: ()};
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let mut u = match UNLOCKED.lock() {
+            Ok(u) => u,
+            Err(e) => e.into_inner(),
+        };
+
+        u.count -= 1;
+        if u.count == 0 {
+            let core = u.core.take();
+            enable_dumps(core, u.dumpable);
+        }
+    }
+}
+
+// Returns the previous core size limit and whether the process was
+// dumpable.
+#[cfg(unix)]
+fn disable_dumps() -> (Option<CoreLimit>, bool) {
+    let dumpable = is_dumpable();
+    set_dumpable(false);
+
+    let mut old = libc::rlimit{rlim_cur: 0, rlim_max: 0};
+    if unsafe { libc::getrlimit(libc::RLIMIT_CORE, &mut old) } != 0 {
+        return (None, dumpable);
+    }
+
+    let none = libc::rlimit{rlim_cur: 0, rlim_max: old.rlim_max};
+    unsafe { libc::setrlimit(libc::RLIMIT_CORE, &none) };
+    return (Some(old), dumpable);
+}
+
+#[cfg(unix)]
+fn enable_dumps(core: Option<CoreLimit>, dumpable: bool) {
+    match core {
+        Some(old) => { unsafe { libc::setrlimit(libc::RLIMIT_CORE, &old) }; },
+        None => (),
+    }
+
+    if dumpable {
+        set_dumpable(true);
+    }
+}
+
+#[cfg(not(unix))]
+fn disable_dumps() -> (Option<CoreLimit>, bool) {
+    (None, false)
+}
+
+#[cfg(not(unix))]
+fn enable_dumps(_core: Option<CoreLimit>, _dumpable: bool) {
+}
+
+// Setuid processes (2, root only dumps) can't be switched back to that
+// mode, they are left undumpable just like ones that started that way.
+#[cfg(target_os = "linux")]
+fn is_dumpable() -> bool {
+    unsafe { libc::prctl(libc::PR_GET_DUMPABLE, 0, 0, 0, 0) == 1 }
+}
+
+#[cfg(target_os = "linux")]
+fn set_dumpable(on: bool) {
+    unsafe { libc::prctl(libc::PR_SET_DUMPABLE, if on { 1 } else { 0 }, 0, 0, 0) };
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn is_dumpable() -> bool {
+    false
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn set_dumpable(_on: bool) {
+}
@@ -1,30 +1,36 @@
 #[cfg(test)]
 mod tests {
     use std::fs;
+    use std::io::Cursor;
     use std::collections::HashMap;
     use item;
+    use crypto;
 
     #[test]
     fn invalid_db_path() {
-        let kc = ::keychain::V3::open("invalid_db_path", "invalid_password");
-        assert_eq!(kc.is_some(), false);
+        match ::keychain::V3::open("invalid_db_path", "invalid_password") {
+            Err(::error::Error::Io(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
     }
 
     #[test]
     fn invalid_db_password() {
-        let kc = ::keychain::V3::open("simple.psafe3", "invalid_password");
-        assert_eq!(kc.is_some(), false);
+        match ::keychain::V3::open("simple.psafe3", "invalid_password") {
+            Err(::error::Error::WrongPassword) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
     }
 
     #[test]
     fn valid_db_password() {
         let kc = ::keychain::V3::open("simple.psafe3", "bogus12345");
-        assert_eq!(kc.is_some(), true);
+        assert_eq!(kc.is_ok(), true);
     }
 
     #[test]
     fn valid_db_size() {
-        let kc = ::keychain::V3::open("simple.psafe3", "bogus12345").expect("Invalid password");
+        let kc = ::keychain::V3::open("simple.psafe3", "bogus12345").expect("Can't open database");
         assert_eq!(kc.len(), 9);
     }
 
@@ -32,12 +38,11 @@ mod tests {
         pub static ref ITEMS: Vec<HashMap<item::Kind,item::Data>> = {
             let mut v = Vec::new();
 
-            // TODO: UUID
-
             // 0
             let mut m = HashMap::new();
-            m.insert(item::Kind::CreateTime, item::Data::Int(1339168618));
-            m.insert(item::Kind::ModifyTime, item::Data::Int(1339168764));
+            m.insert(item::Kind::UUID, item::Data::Raw(vec![0x67, 0xe0, 0x5e, 0x25, 0xa3, 0x3c, 0x42, 0x35, 0xb5, 0x71, 0x9c, 0xec, 0x9b, 0xd5, 0xe6, 0x41]));
+            m.insert(item::Kind::CreateTime, item::Data::Time(item::Time::from_secs(1339168618)));
+            m.insert(item::Kind::ModifyTime, item::Data::Time(item::Time::from_secs(1339168764)));
             m.insert(item::Kind::Title, item::Data::Text("Test eight".to_string()));
             m.insert(item::Kind::Username, item::Data::Text("user8".to_string()));
             m.insert(item::Kind::Password, item::Data::Text("my password".to_string()));
@@ -48,10 +53,11 @@ mod tests {
 
             // 1
             let mut m = HashMap::new();
-            m.insert(item::Kind::CreateTime, item::Data::Int(1311392620));
-            m.insert(item::Kind::AccessTime, item::Data::Int(1311400802));
-            m.insert(item::Kind::ExpiryTime, item::Data::Int(1327636140));
-            m.insert(item::Kind::ModifyTime, item::Data::Int(1311907753));
+            m.insert(item::Kind::UUID, item::Data::Raw(vec![0xe8, 0x74, 0x98, 0x80, 0x30, 0x94, 0x4b, 0xa6, 0xba, 0xd2, 0xa0, 0x3b, 0x75, 0x69, 0x7a, 0xc2]));
+            m.insert(item::Kind::CreateTime, item::Data::Time(item::Time::from_secs(1311392620)));
+            m.insert(item::Kind::AccessTime, item::Data::Time(item::Time::from_secs(1311400802)));
+            m.insert(item::Kind::ExpiryTime, item::Data::Time(item::Time::from_secs(1327636140)));
+            m.insert(item::Kind::ModifyTime, item::Data::Time(item::Time::from_secs(1311907753)));
             m.insert(item::Kind::Title, item::Data::Text("Test Four".to_string()));
             m.insert(item::Kind::Username, item::Data::Text("user4".to_string()));
             m.insert(item::Kind::Password, item::Data::Text("pass4".to_string()));
@@ -61,10 +67,11 @@ mod tests {
 
             // 2
             let mut m = HashMap::new();
-            m.insert(item::Kind::CreateTime, item::Data::Int(1311386977));
-            m.insert(item::Kind::AccessTime, item::Data::Int(1311400799));
-            m.insert(item::Kind::ExpiryTime, item::Data::Int(1311994130));
-            m.insert(item::Kind::ModifyTime, item::Data::Int(1311907737));
+            m.insert(item::Kind::UUID, item::Data::Raw(vec![0x7b, 0xed, 0xc6, 0x8b, 0x40, 0xa5, 0x43, 0x48, 0xbc, 0x2b, 0x33, 0xdc, 0x50, 0x77, 0x2b, 0xb3]));
+            m.insert(item::Kind::CreateTime, item::Data::Time(item::Time::from_secs(1311386977)));
+            m.insert(item::Kind::AccessTime, item::Data::Time(item::Time::from_secs(1311400799)));
+            m.insert(item::Kind::ExpiryTime, item::Data::Time(item::Time::from_secs(1311994130)));
+            m.insert(item::Kind::ModifyTime, item::Data::Time(item::Time::from_secs(1311907737)));
             m.insert(item::Kind::Group, item::Data::Text("Test".to_string()));
             m.insert(item::Kind::Title, item::Data::Text("Test One".to_string()));
             m.insert(item::Kind::Username, item::Data::Text("user2".to_string()));
@@ -76,8 +83,9 @@ mod tests {
 
             // 3
             let mut m = HashMap::new();
-            m.insert(item::Kind::CreateTime, item::Data::Int(1339168618));
-            m.insert(item::Kind::ModifyTime, item::Data::Int(1339168719));
+            m.insert(item::Kind::UUID, item::Data::Raw(vec![0x30, 0xee, 0x4d, 0xac, 0x70, 0xc3, 0x41, 0x96, 0xb7, 0x9a, 0xa4, 0xbd, 0x95, 0x50, 0x85, 0xac]));
+            m.insert(item::Kind::CreateTime, item::Data::Time(item::Time::from_secs(1339168618)));
+            m.insert(item::Kind::ModifyTime, item::Data::Time(item::Time::from_secs(1339168719)));
             m.insert(item::Kind::Title, item::Data::Text("Test seven".to_string()));
             m.insert(item::Kind::Username, item::Data::Text("user7".to_string()));
             m.insert(item::Kind::Password, item::Data::Text("my password".to_string()));
@@ -90,9 +98,10 @@ mod tests {
 
             // 4
             let mut m = HashMap::new();
-            m.insert(item::Kind::CreateTime, item::Data::Int(1311386990));
-            m.insert(item::Kind::AccessTime, item::Data::Int(1311400798));
-            m.insert(item::Kind::ModifyTime, item::Data::Int(1311907761));
+            m.insert(item::Kind::UUID, item::Data::Raw(vec![0xe4, 0x4b, 0x9f, 0xb9, 0xeb, 0x43, 0x49, 0xb7, 0xb2, 0xe1, 0x05, 0x85, 0x30, 0xc1, 0xb9, 0x43]));
+            m.insert(item::Kind::CreateTime, item::Data::Time(item::Time::from_secs(1311386990)));
+            m.insert(item::Kind::AccessTime, item::Data::Time(item::Time::from_secs(1311400798)));
+            m.insert(item::Kind::ModifyTime, item::Data::Time(item::Time::from_secs(1311907761)));
             m.insert(item::Kind::Title, item::Data::Text("Test Two".to_string()));
             m.insert(item::Kind::Username, item::Data::Text("user3".to_string()));
             m.insert(item::Kind::Password, item::Data::Text("pass3".to_string()));
@@ -102,7 +111,8 @@ mod tests {
 
             // 5
             let mut m = HashMap::new();
-            m.insert(item::Kind::CreateTime, item::Data::Int(1339362429));
+            m.insert(item::Kind::UUID, item::Data::Raw(vec![0x15, 0x47, 0xfc, 0xd2, 0x0e, 0x8c, 0x40, 0xdf, 0xaa, 0x4c, 0x10, 0x2a, 0x79, 0xe1, 0x26, 0x1b]));
+            m.insert(item::Kind::CreateTime, item::Data::Time(item::Time::from_secs(1339362429)));
             m.insert(item::Kind::Group, item::Data::Text("Test".to_string()));
             m.insert(item::Kind::Title, item::Data::Text("Test Nine".to_string()));
             m.insert(item::Kind::Username, item::Data::Text("user9".to_string()));
@@ -113,8 +123,9 @@ mod tests {
 
             // 6
             let mut m = HashMap::new();
-            m.insert(item::Kind::CreateTime, item::Data::Int(1339168618));
-            m.insert(item::Kind::ModifyTime, item::Data::Int(1339168666));
+            m.insert(item::Kind::UUID, item::Data::Raw(vec![0x53, 0xbe, 0x38, 0xd5, 0x83, 0x05, 0x46, 0x88, 0x9d, 0xe5, 0xd6, 0x77, 0x4a, 0xea, 0x00, 0xbe]));
+            m.insert(item::Kind::CreateTime, item::Data::Time(item::Time::from_secs(1339168618)));
+            m.insert(item::Kind::ModifyTime, item::Data::Time(item::Time::from_secs(1339168666)));
             m.insert(item::Kind::Title, item::Data::Text("Test six".to_string()));
             m.insert(item::Kind::Username, item::Data::Text("user6".to_string()));
             m.insert(item::Kind::Password, item::Data::Text("my password".to_string()));
@@ -125,9 +136,10 @@ mod tests {
 
             // 7
             let mut m = HashMap::new();
-            m.insert(item::Kind::CreateTime, item::Data::Int(1311386913));
-            m.insert(item::Kind::AccessTime, item::Data::Int(1311400800));
-            m.insert(item::Kind::ModifyTime, item::Data::Int(1311907724));
+            m.insert(item::Kind::UUID, item::Data::Raw(vec![0x6e, 0xf5, 0xc1, 0xf3, 0x2c, 0xa5, 0x4e, 0x05, 0xa0, 0x93, 0x20, 0xc8, 0x98, 0x97, 0x3c, 0x15]));
+            m.insert(item::Kind::CreateTime, item::Data::Time(item::Time::from_secs(1311386913)));
+            m.insert(item::Kind::AccessTime, item::Data::Time(item::Time::from_secs(1311400800)));
+            m.insert(item::Kind::ModifyTime, item::Data::Time(item::Time::from_secs(1311907724)));
             m.insert(item::Kind::Group, item::Data::Text("Test".to_string()));
             m.insert(item::Kind::Title, item::Data::Text("Test One".to_string()));
             m.insert(item::Kind::Username, item::Data::Text("user1".to_string()));
@@ -138,7 +150,8 @@ mod tests {
 
             // 8
             let mut m = HashMap::new();
-            m.insert(item::Kind::CreateTime, item::Data::Int(1339168618));
+            m.insert(item::Kind::UUID, item::Data::Raw(vec![0xb8, 0x0d, 0x5e, 0xfd, 0xb4, 0x6a, 0x4f, 0x5d, 0x88, 0xd2, 0xd5, 0x8a, 0xad, 0x22, 0x0e, 0x17]));
+            m.insert(item::Kind::CreateTime, item::Data::Time(item::Time::from_secs(1339168618)));
             m.insert(item::Kind::Title, item::Data::Text("Test Five".to_string()));
             m.insert(item::Kind::Username, item::Data::Text("user5".to_string()));
             m.insert(item::Kind::Password, item::Data::Text("my password".to_string()));
@@ -152,7 +165,7 @@ mod tests {
     }
 
     fn validate(path: &str, password: &str) {
-        let kc = ::keychain::V3::open(path, password).expect("Invalid password");
+        let kc = ::keychain::V3::open(path, password).expect("Can't open database");
 
         let mut z = 0;
         for i in kc.iter() {
@@ -160,7 +173,11 @@ mod tests {
 
             for (k, ref v) in ITEMS[z].iter() {
                 println!("? {:?}", k);
-                assert_eq!(i.get(*k).expect("Can't get the item while iterating"), *v);
+                match i.get(*k).expect("Can't get the item while iterating") {
+                    &item::Data::Sealed(_) => assert!(k.is_sensitive()),
+                    d => assert_eq!(d, *v),
+                }
+                i.reveal(*k, |d| assert_eq!(d.unwrap(), *v));
             }
 
             z = z + 1
@@ -192,7 +209,1113 @@ mod tests {
             kc.insert(item);
         }
 
-        kc.save("bogus12345");
+        kc.save("bogus12345").expect("Can't save database");
         validate("simple2.psafe3", "bogus12345");
     }
+
+    #[test]
+    fn header() {
+        let kc = ::keychain::V3::open("simple.psafe3", "bogus12345").expect("Can't open database");
+        assert!(kc.header().get(item::Kind::Version).is_some());
+        assert!(kc.header().get(item::Kind::UUID).is_some());
+    }
+
+    #[test]
+    fn save_header() {
+        let path = "simple3.psafe3";
+
+        fs::copy("simple.psafe3", path).expect("Can't copy database");
+
+        let mut kc = ::keychain::V3::open(path, "bogus12345").expect("Can't open database");
+        let uuid = kc.header().get(item::Kind::UUID).expect("No database UUID").clone();
+        let nprefs = kc.header().get_all(item::Kind::NonDefaultPrefs).len();
+
+        kc.header_mut().insert(item::Kind::DbName, &item::Data::Text("name".to_string()));
+        kc.header_mut().insert(item::Kind::DbDescription, &item::Data::Text("description".to_string()));
+        kc.header_mut().push(item::Kind::EmptyGroups, &item::Data::Text("a".to_string()));
+        kc.header_mut().push(item::Kind::EmptyGroups, &item::Data::Text("b".to_string()));
+        kc.save("bogus12345").expect("Can't save database");
+
+        let kc = ::keychain::V3::open(path, "bogus12345").expect("Can't open database");
+        let hdr = kc.header();
+        assert_eq!(hdr.iter().next().expect("Empty header").def.kind, item::Kind::Version);
+        assert_eq!(hdr.get(item::Kind::UUID), Some(&uuid));
+        assert_eq!(hdr.get_all(item::Kind::NonDefaultPrefs).len(), nprefs);
+        assert_eq!(hdr.get(item::Kind::DbName), Some(&item::Data::Text("name".to_string())));
+        assert_eq!(hdr.get(item::Kind::DbDescription), Some(&item::Data::Text("description".to_string())));
+        assert_eq!(hdr.get_all(item::Kind::EmptyGroups), vec![&item::Data::Text("a".to_string()), &item::Data::Text("b".to_string())]);
+        assert!(hdr.get(item::Kind::LastSaveTime).is_some());
+        assert!(hdr.get(item::Kind::LastSaveWhat).is_some());
+        assert_eq!(kc.len(), 9);
+    }
+
+    #[test]
+    fn uuid() {
+        let path = "simple5.psafe3";
+        fs::remove_file(path).ok();
+
+        let mut kc = ::keychain::V3::new(path);
+        kc.insert(item::new());
+        kc.insert(item::new());
+        kc.save("bogus12345").expect("Can't save database");
+
+        let kc = ::keychain::V3::open(path, "bogus12345").expect("Can't open database");
+        let uuids: Vec<item::Uuid> = kc.iter().map(|i| i.uuid().expect("Missing UUID")).collect();
+        assert_eq!(uuids.len(), 2);
+        assert!(uuids[0] != uuids[1]);
+        for u in uuids {
+            assert_eq!(u.0[6] >> 4, 4);
+            assert_eq!(u.0[8] >> 6, 2);
+        }
+
+        let u = item::Uuid([0x67, 0xe0, 0x5e, 0x25, 0xa3, 0x3c, 0x42, 0x35, 0xb5, 0x71, 0x9c, 0xec, 0x9b, 0xd5, 0xe6, 0x41]);
+        assert_eq!(u.to_string(), "67e05e25-a33c-4235-b571-9cec9bd5e641");
+    }
+
+    fn unknown_item() -> item::Item {
+        let mut i = item::new();
+        i.insert(item::Kind::UUID, &item::Data::Raw(vec![0x42; 16]));
+        i.push(item::Kind::Unknown(0x70), &item::Data::Raw(b"from the future".to_vec()));
+        i.insert(item::Kind::Title, &item::Data::Text("unknown".to_string()));
+        i.push(item::Kind::Unknown(0x03), &item::Data::Raw(vec![0xff, 0xfe, 0xfd]));
+        i.push(item::Kind::Unknown(0x07), &item::Data::Raw(b"5a1b2c3d".to_vec()));
+        i.push(item::Kind::Unknown(0x15), &item::Data::Raw(vec![]));
+        i.push(item::Kind::Unknown(0xdf), &item::Data::Raw(vec![0; 33]));
+        i
+    }
+
+    fn plaintext(items: &[item::Item]) -> Vec<u8> {
+        let mut mac = crypto::HMAC::new(&[0; 32]);
+        let mut c = Cursor::new(Vec::new());
+        for i in items {
+            i.serialize(&mut c, &mut mac);
+            item::FIELD_END.serialize(&mut c, &mut mac);
+        }
+        c.into_inner()
+    }
+
+    #[test]
+    fn lossless() {
+        crypto::init();
+
+        let mut hdr = item::new();
+        hdr.insert(item::Kind::Version, &item::Data::Short(0x030d));
+        hdr.push(item::Kind::Unknown(0x40), &item::Data::Raw(b"header from the future".to_vec()));
+        let plain = plaintext(&[hdr, unknown_item(), unknown_item()]);
+
+        let mut mac = crypto::HMAC::new(&[0; 32]);
+        let mut c = Cursor::new(&plain[..]);
+        let mut items = Vec::new();
+        items.push(item::parse(&mut mac, &item::HEADER, &mut c).expect("Can't parse header").expect("No header"));
+        loop {
+            match item::parse(&mut mac, &item::DATA, &mut c).expect("Can't parse item") {
+                Some(i) => items.push(i),
+                None => break,
+            }
+        }
+        assert_eq!(items.len(), 3);
+        assert_eq!(plaintext(&items), plain);
+
+        let path = "simple4.psafe3";
+        fs::remove_file(path).ok();
+
+        let mut kc = ::keychain::V3::new(path);
+        kc.set_update_times(false);
+        kc.header_mut().push(item::Kind::Unknown(0x40), &item::Data::Raw(b"header from the future".to_vec()));
+        kc.insert(unknown_item());
+        kc.save("bogus12345").expect("Can't save database");
+
+        let kc = ::keychain::V3::open(path, "bogus12345").expect("Can't open database");
+        assert_eq!(kc.header().get(item::Kind::Unknown(0x40)), Some(&item::Data::Raw(b"header from the future".to_vec())));
+        assert_eq!(kc.len(), 1);
+
+        let mut c = Cursor::new(Vec::new());
+        kc.iter().next().expect("No items").serialize(&mut c, &mut crypto::HMAC::new(&[0; 32]));
+        let mut e = Cursor::new(Vec::new());
+        unknown_item().serialize(&mut e, &mut crypto::HMAC::new(&[0; 32]));
+        assert_eq!(c.into_inner(), e.into_inner());
+    }
+
+    fn corrupt(path: &str, f: &Fn(&mut Vec<u8>)) -> ::error::Error {
+        let mut d = fs::read("simple.psafe3").expect("Can't read database");
+        f(&mut d);
+        fs::write(path, &d).expect("Can't write database");
+
+        match ::keychain::V3::open(path, "bogus12345") {
+            Err(e) => e,
+            Ok(_) => panic!("Corrupted database opened successfully"),
+        }
+    }
+
+    #[test]
+    fn errors() {
+        match corrupt("errors1.psafe3", &|d| d[0] = b'X') {
+            ::error::Error::BadTag => (),
+            e => panic!("Unexpected error {:?}", e),
+        }
+
+        match corrupt("errors2.psafe3", &|d| d.truncate(100)) {
+            ::error::Error::Truncated => (),
+            e => panic!("Unexpected error {:?}", e),
+        }
+
+        match corrupt("errors3.psafe3", &|d| { let n = d.len(); d[n - 1] ^= 1; }) {
+            ::error::Error::HmacMismatch => (),
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn entry_fields() {
+        let fields = vec![
+            (0x08, item::Kind::PasswordModifyTime, item::Data::Time(item::Time::from_secs(1311907753))),
+            (0x0d, item::Kind::URL, item::Data::Text("https://pwsafe.org".to_string())),
+            (0x11, item::Kind::PasswordExpiryInterval, item::Data::Int(90)),
+            (0x18, item::Kind::PasswordPolicyName, item::Data::Text("pin".to_string())),
+            (0x19, item::Kind::KeyboardShortcut, item::Data::Int(0x00020041)),
+            (0x1a, item::Kind::AttachmentRef, item::Data::Raw(vec![0x42; 16])),
+            (0x1b, item::Kind::TwoFactorKey, item::Data::Raw(b"12345678901234567890".to_vec())),
+            (0x1c, item::Kind::CreditCardNumber, item::Data::Text("4111111111111111".to_string())),
+            (0x1d, item::Kind::CreditCardExpiration, item::Data::Text("12/30".to_string())),
+            (0x1e, item::Kind::CreditCardVerifValue, item::Data::Text("123".to_string())),
+            (0x1f, item::Kind::CreditCardPin, item::Data::Text("1234".to_string())),
+            (0x20, item::Kind::QRCode, item::Data::Text("otpauth://totp/pwsf".to_string())),
+            (0x21, item::Kind::TotpConfig, item::Data::Byte(0)),
+            (0x22, item::Kind::TotpLength, item::Data::Byte(6)),
+            (0x23, item::Kind::TotpTimeStep, item::Data::Byte(30)),
+            (0x24, item::Kind::TotpStartTime, item::Data::Time(item::Time::from_secs(0))),
+        ];
+
+        let mut i = item::new();
+        for &(_, kind, ref data) in fields.iter() {
+            i.insert(kind, data);
+        }
+
+        let d = plaintext(&[i]);
+        let items = parse_all(&d).unwrap();
+        assert_eq!(items.len(), 1);
+
+        let mut c = Cursor::new(&d[..]);
+        for &(tp, kind, ref data) in fields.iter() {
+            assert_eq!(kind.is_sensitive(), kind == item::Kind::TwoFactorKey || kind == item::Kind::CreditCardNumber ||
+                       kind == item::Kind::CreditCardVerifValue || kind == item::Kind::CreditCardPin);
+            items[0].reveal(kind, |d| assert_eq!(d, Some(data)));
+
+            // length, type byte, data padded to the block size
+            let pos = c.position() as usize;
+            assert_eq!(d[pos + 4], tp);
+            let len = d[pos] as usize;
+            c.set_position((pos + (5 + len + 15) / 16 * 16) as u64);
+        }
+    }
+
+    #[test]
+    fn times() {
+        // 32, 40 and 64-bit integers and 8 hex characters
+        for &(ref data, secs) in [
+            (vec![0x6a, 0x17, 0xd2, 0x4f], 1339168618),
+            (vec![0x6a, 0x17, 0xd2, 0x4f, 0x01], 0x14fd2176a),
+            (vec![0x6a, 0x17, 0xd2, 0x4f, 0, 0, 0, 0], 1339168618),
+            (b"4fd2176a".to_vec(), 1339168618),
+            (b"4FD2176A".to_vec(), 1339168618),
+        ].iter() {
+            let mut i = item::new();
+            i.push(item::Kind::Unknown(0x07), &item::Data::Raw(data.clone()));
+
+            let d = plaintext(&[i]);
+            let items = parse_all(&d).unwrap();
+            assert_eq!(items[0].time(item::Kind::CreateTime).unwrap().secs(), secs);
+            assert_eq!(plaintext(&items), d);
+        }
+
+        let t = item::Time::from_secs(1339168618);
+        assert_eq!(t.to_iso8601(), "2012-06-08T15:16:58Z");
+        assert_eq!(t.to_utc_string(), "2012-06-08 15:16:58 UTC");
+        assert_eq!(item::Time::from_secs(951782400).to_iso8601(), "2000-02-29T00:00:00Z");
+        assert_eq!(item::Time::from_secs(4102444800).to_iso8601(), "2100-01-01T00:00:00Z");
+        assert!(item::Time::from_secs(0).to_local_string().starts_with("19"));
+        assert_eq!(item::Time::from_secs(253402300799).to_iso8601(), "9999-12-31T23:59:59Z");
+        assert_eq!(item::Time::from_secs(u64::max_value()).to_iso8601(), "9999-12-31T23:59:59Z");
+        assert!(item::Time::from_secs(i64::max_value() as u64).to_local_string().len() > 0);
+
+        let kc = ::keychain::V3::open("simple.psafe3", "bogus12345").unwrap();
+        let expired = kc.find_time(item::Kind::ExpiryTime, None, Some(item::Time::from_secs(1311994131)));
+        assert_eq!(expired.iter().map(|i| i.name()).collect::<Vec<String>>(), vec!["Test.Test One"]);
+        assert_eq!(kc.find_time(item::Kind::ExpiryTime, Some(t), None).len(), 0);
+        assert_eq!(kc.find_time(item::Kind::CreateTime, Some(t), None).len(), 5);
+    }
+
+    #[derive(Debug)]
+    struct FixedClock(u64);
+
+    impl ::keychain::Clock for FixedClock {
+        fn now(&self) -> item::Time { item::Time::from_secs(self.0) }
+    }
+
+    #[test]
+    fn entry_times() {
+        let path = "entry_times.psafe3";
+        fs::remove_file(path).ok();
+
+        let time = |kc: &::keychain::V3, uuid, kind| kc.get(uuid).unwrap().time(kind).map(|t| t.secs());
+
+        let mut kc = ::keychain::V3::new(path);
+        kc.set_clock(Box::new(FixedClock(1000)));
+
+        let mut i = titled("times");
+        i.insert(item::Kind::Password, &item::Data::Text("pass".to_string()));
+        kc.insert(i);
+        let uuid = kc.iter().next().unwrap().uuid().unwrap();
+        assert_eq!(time(&kc, &uuid, item::Kind::CreateTime), Some(1000));
+        assert_eq!(time(&kc, &uuid, item::Kind::ModifyTime), Some(1000));
+        assert_eq!(time(&kc, &uuid, item::Kind::AccessTime), None);
+
+        // password modification time only changes with the password
+        kc.set_clock(Box::new(FixedClock(2000)));
+        assert!(kc.modify(&uuid, |i| i.insert(item::Kind::Username, &item::Data::Text("user".to_string()))));
+        assert_eq!(time(&kc, &uuid, item::Kind::ModifyTime), Some(2000));
+        assert_eq!(time(&kc, &uuid, item::Kind::PasswordModifyTime), None);
+
+        kc.set_clock(Box::new(FixedClock(3000)));
+        kc.modify(&uuid, |i| i.insert(item::Kind::Password, &item::Data::Text("new pass".to_string())));
+        assert_eq!(time(&kc, &uuid, item::Kind::PasswordModifyTime), Some(3000));
+        assert_eq!(time(&kc, &uuid, item::Kind::CreateTime), Some(1000));
+
+        kc.set_clock(Box::new(FixedClock(4000)));
+        assert!(kc.touch(&uuid));
+        assert_eq!(time(&kc, &uuid, item::Kind::AccessTime), Some(4000));
+
+        kc.save("bogus12345").expect("Can't save database");
+        let mut kc = ::keychain::V3::open(path, "bogus12345").expect("Can't open database");
+        assert_eq!(time(&kc, &uuid, item::Kind::AccessTime), Some(4000));
+        assert_eq!(kc.header().time(item::Kind::LastSaveTime).map(|t| t.secs()), Some(4000));
+
+        // read-only workflows leave times alone
+        kc.set_clock(Box::new(FixedClock(5000)));
+        kc.set_update_times(false);
+        assert!(!kc.touch(&uuid));
+        kc.modify(&uuid, |_| ());
+        kc.insert(titled("untouched"));
+        assert_eq!(time(&kc, &uuid, item::Kind::AccessTime), Some(4000));
+        assert_eq!(time(&kc, &uuid, item::Kind::ModifyTime), Some(3000));
+        assert_eq!(kc.iter().last().unwrap().time(item::Kind::CreateTime), None);
+    }
+
+    #[test]
+    fn password_history() {
+        let h = ::history::PasswordHistory::parse("1ff00").unwrap();
+        assert!(h.enabled);
+        assert_eq!(h.max, 255);
+        assert!(h.entries.is_empty());
+
+        let h = ::history::PasswordHistory::parse("10302000003e80003abc00000bb80004\u{e9}t\u{e9}!").unwrap();
+        assert_eq!(h.entries.len(), 2);
+        assert_eq!(h.entries[0].time.secs(), 1000);
+        assert_eq!(&*h.entries[0].password, "abc");
+        assert_eq!(&*h.entries[1].password, "\u{e9}t\u{e9}!");
+        assert_eq!(::history::PasswordHistory::parse(&h.to_data().to_string()), Some(h));
+
+        for bad in &["", "2ff00", "1ff01", "1ff0100000001000", "1ff00x", "1ff0100000001000ab"] {
+            assert_eq!(::history::PasswordHistory::parse(bad), None);
+        }
+
+        let mut kc = ::keychain::V3::new("password_history.psafe3");
+        kc.set_clock(Box::new(FixedClock(1000)));
+
+        let mut i = titled("history");
+        i.insert(item::Kind::Password, &item::Data::Text("first".to_string()));
+        i.set_history(&::history::PasswordHistory::new(2));
+        kc.insert(i);
+        let uuid = kc.iter().next().unwrap().uuid().unwrap();
+
+        for (t, p) in [(2000, "second"), (3000, "third"), (4000, "fourth")].iter() {
+            kc.set_clock(Box::new(FixedClock(*t)));
+            kc.modify(&uuid, |i| i.insert(item::Kind::Password, &item::Data::Text(p.to_string())));
+        }
+        kc.modify(&uuid, |i| i.insert(item::Kind::Username, &item::Data::Text("user".to_string())));
+
+        // oldest dropped beyond max, each with the time it was set
+        let h = kc.get(&uuid).unwrap().history().unwrap();
+        let old: Vec<(u64, String)> = h.entries.iter().map(|e| (e.time.secs(), e.password.to_string())).collect();
+        assert_eq!(old, vec![(2000, "second".to_string()), (3000, "third".to_string())]);
+
+        // nothing recorded when disabled
+        let mut disabled = h.clone();
+        disabled.enabled = false;
+        kc.modify(&uuid, |i| {
+            i.set_history(&disabled);
+            i.insert(item::Kind::Password, &item::Data::Text("fifth".to_string()));
+        });
+        assert_eq!(kc.get(&uuid).unwrap().history(), Some(disabled));
+
+        // entries without history get the database default, none unless
+        // the database keeps it
+        let changes = |kc: &mut ::keychain::V3| {
+            kc.insert(titled("no history"));
+            let uuid = kc.iter().last().unwrap().uuid().unwrap();
+            for p in ["pass", "other", "third"].iter() {
+                kc.modify(&uuid, |i| i.insert(item::Kind::Password, &item::Data::Text(p.to_string())));
+            }
+            kc.get(&uuid).unwrap().history()
+        };
+
+        assert_eq!(changes(&mut kc), None);
+
+        kc.header_mut().insert(item::Kind::NonDefaultPrefs, &item::Data::Text("B 29 1".to_string()));
+        let h = changes(&mut kc).unwrap();
+        assert_eq!(h.max, 3);
+        assert_eq!(h.entries.iter().map(|e| e.password.to_string()).collect::<Vec<String>>(), vec!["pass", "other"]);
+
+        kc.header_mut().insert(item::Kind::NonDefaultPrefs, &item::Data::Text("B 29 1 S 6 |a b| I 8 1".to_string()));
+        let h = changes(&mut kc).unwrap();
+        assert_eq!(h.entries.iter().map(|e| e.password.to_string()).collect::<Vec<String>>(), vec!["other"]);
+
+        kc.header_mut().insert(item::Kind::NonDefaultPrefs, &item::Data::Text("B 29 0 I 8 1".to_string()));
+        assert_eq!(changes(&mut kc), None);
+    }
+
+    #[test]
+    fn password_policy() {
+        use policy::PasswordPolicy;
+
+        let p = PasswordPolicy::parse("f00000e001001001001").unwrap();
+        assert_eq!(p, PasswordPolicy{length: 14, ..Default::default()});
+        assert_eq!(p.to_data().to_string(), "f00000e001001001001");
+        let p = PasswordPolicy::parse("a40001400a000002000").unwrap();
+        assert!(p.lowercase && !p.uppercase && p.digits && !p.symbols && p.easy_vision);
+        assert_eq!((p.length, p.min_lowercase, p.min_digits), (20, 10, 2));
+        for bad in &["", "f00000e00100100100", "f00000e0010010010010", "g00000e001001001001"] {
+            assert_eq!(PasswordPolicy::parse(bad), None);
+        }
+
+        let count = |s: &str, set: &str| s.chars().filter(|&c| set.contains(c)).count();
+        for _ in 0..20 {
+            let p = PasswordPolicy{length: 16, min_lowercase: 3, min_uppercase: 4, min_digits: 5, ..Default::default()};
+            let s = p.generate(None).unwrap();
+            assert_eq!(s.chars().count(), 16);
+            assert!(count(&s, "abcdefghijklmnopqrstuvwxyz") >= 3);
+            assert!(count(&s, "ABCDEFGHIJKLMNOPQRSTUVWXYZ") >= 4);
+            assert!(count(&s, "0123456789") >= 5);
+            assert!(count(&s, ::policy::SYMBOLS) >= 1);
+
+            let p = PasswordPolicy{easy_vision: true, length: 40, ..Default::default()};
+            let s = p.generate(None).unwrap();
+            assert_eq!(count(&s, "lIOSZ0125"), 0);
+            assert_eq!(count(&s, ::policy::EASY_SYMBOLS), count(&s, ::policy::SYMBOLS));
+
+            let p = PasswordPolicy{hex: true, length: 32, ..Default::default()};
+            assert_eq!(count(&p.generate(None).unwrap(), "0123456789abcdef"), 32);
+
+            let p = PasswordPolicy{lowercase: false, uppercase: false, digits: false, min_symbols: 8, ..Default::default()};
+            assert_eq!(count(&p.generate(Some("!!?")).unwrap(), "!?"), 12);
+        }
+
+        // impossible policies
+        assert!(PasswordPolicy{length: 3, ..Default::default()}.generate(None).is_err());
+        assert!(PasswordPolicy{length: 0, ..Default::default()}.generate(None).is_err());
+        let none = PasswordPolicy{lowercase: false, uppercase: false, digits: false, symbols: false, ..Default::default()};
+        assert!(none.generate(None).is_err());
+
+        // policy and symbols of the entry
+        let mut i = titled("policy");
+        i.insert(item::Kind::PasswordPolicy, &item::Data::Text("1000020000000000004".to_string()));
+        i.insert(item::Kind::PasswordSymbols, &item::Data::Text("#%".to_string()));
+        assert_eq!(i.policy().map(|p| p.length), Some(32));
+        assert_eq!(count(&i.generate_password().unwrap(), "#%"), 32);
+        assert_eq!(titled("default").generate_password().unwrap().len(), 12);
+    }
+
+    #[test]
+    fn named_policies() {
+        use policy::{NamedPolicies, NamedPolicy, PasswordPolicy};
+
+        let s = "0203Webf000014001001002001000cLegacy-8chare00008001001001000002@#";
+        let p = NamedPolicies::parse(s).unwrap();
+        assert_eq!(p.policies.len(), 2);
+        assert_eq!(p.policies[0].name, "Web");
+        assert_eq!(p.policies[0].policy, PasswordPolicy{length: 20, min_digits: 2, ..Default::default()});
+        assert_eq!(p.policies[1].name, "Legacy-8char");
+        assert_eq!(p.policies[1].symbols, "@#");
+        assert_eq!(p.to_data().to_string(), s);
+        assert_eq!(NamedPolicies::parse(&s[..s.len() - 1]), None);
+        assert_eq!(NamedPolicies::parse(&format!("{}x", s)), None);
+
+        let path = "named_policies.psafe3";
+        fs::remove_file(path).ok();
+
+        let mut kc = ::keychain::V3::new(path);
+        assert!(kc.policies().policies.is_empty());
+
+        let mut p = NamedPolicies::default();
+        let pin = PasswordPolicy{lowercase: false, uppercase: false, symbols: false, length: 4, min_digits: 4, ..Default::default()};
+        p.insert(NamedPolicy{name: "PIN".to_string(), policy: pin.clone(), symbols: String::new()});
+        p.insert(NamedPolicy{name: "Web".to_string(), policy: Default::default(), symbols: String::new()});
+        p.insert(NamedPolicy{name: "PIN".to_string(), policy: pin.clone(), symbols: "!".to_string()});
+        assert_eq!(p.policies.len(), 2);
+        assert!(p.remove("Web"));
+        assert!(!p.remove("Web"));
+        kc.set_policies(&p);
+
+        let mut i = titled("pin");
+        i.insert(item::Kind::PasswordPolicyName, &item::Data::Text("PIN".to_string()));
+        i.insert(item::Kind::PasswordPolicy, &item::Data::Text("f00000e001001001001".to_string()));
+        kc.insert(i);
+        kc.save("bogus12345").expect("Can't save database");
+
+        let mut kc = ::keychain::V3::open(path, "bogus12345").expect("Can't open database");
+        assert_eq!(kc.policies(), p);
+
+        // the named policy wins over the entry's own one
+        let uuid = kc.iter().next().unwrap().uuid().unwrap();
+        assert_eq!(kc.policy(kc.get(&uuid).unwrap()).unwrap(), (pin, "!".to_string()));
+        let password = kc.generate_password(kc.get(&uuid).unwrap()).unwrap();
+        assert_eq!(password.len(), 4);
+        assert!(password.chars().all(|c| c.is_digit(10)));
+
+        kc.set_policies(&NamedPolicies::default());
+        assert!(kc.header().get(item::Kind::NamedPasswordPolicies).is_none());
+        assert!(kc.generate_password(kc.get(&uuid).unwrap()).is_err());
+    }
+
+    #[test]
+    fn passphrases() {
+        use passphrase::{Passphrase, Wordlist};
+        use policy::PasswordPolicy;
+
+        let list = Wordlist::parse("11111\tapple\n11112\tbanana\n11113\tcherry\n11114\tdate\n").unwrap();
+        assert_eq!(list.len(), 4);
+        assert_eq!(Wordlist::parse("apple\nbanana\ncherry\ndate\n\n"), Some(list.clone()));
+        assert_eq!(Wordlist::parse("11111\tapple\n11112\tapple\n"), None);
+
+        let p = Passphrase{words: 5, separator: " ".to_string(), capitalize: true, digit: true};
+        for _ in 0..20 {
+            let s = p.generate(&list).unwrap();
+            let words: Vec<&str> = s.split(' ').collect();
+            assert_eq!(words.len(), 5);
+            assert!(words.iter().all(|w| w.starts_with(|c: char| c.is_uppercase())));
+            assert_eq!(s.chars().filter(|c| c.is_digit(10)).count(), 1);
+        }
+        assert_eq!(Passphrase{words: 6, ..Default::default()}.entropy(&list), 12.0);
+        assert!((p.entropy(&list) - (10.0 + 10f64.log2() + 5f64.log2())).abs() < 1e-9);
+        assert!(Passphrase{words: 0, ..Default::default()}.generate(&list).is_err());
+
+        // consonants and vowels alternate, mixed in classes replace letters
+        let p = PasswordPolicy{pronounceable: true, symbols: false, digits: false, min_uppercase: 2, ..Default::default()};
+        for _ in 0..20 {
+            let s = p.generate(None).unwrap();
+            assert_eq!(s.chars().filter(|c| c.is_uppercase()).count(), 2);
+            for (i, c) in s.to_lowercase().chars().enumerate() {
+                assert_eq!("aeiou".contains(c), i % 2 == 1);
+            }
+        }
+
+        let p = PasswordPolicy{pronounceable: true, uppercase: false, min_digits: 3, ..Default::default()};
+        for _ in 0..20 {
+            let s = p.generate(Some("*")).unwrap();
+            assert_eq!(s.chars().filter(|c| c.is_digit(10)).count(), 3);
+            assert_eq!(s.chars().filter(|&c| c == '*').count(), 1);
+            assert_eq!(s.chars().filter(|c| c.is_lowercase()).count(), 8);
+        }
+        let letters = 8.0 * (17f64 * 5.0).log2() / 2.0;
+        let positions = (12f64 * 11.0 * 10.0 / 6.0).log2() + 9f64.log2();
+        assert!((p.entropy(Some("*")) - (letters + 3.0 * 10f64.log2() + positions)).abs() < 1e-9);
+        assert!(PasswordPolicy{pronounceable: true, length: 3, min_digits: 3, ..Default::default()}.generate(None).is_err());
+
+        assert!((PasswordPolicy::default().entropy(None) - 12.0 * 91f64.log2()).abs() < 1e-9);
+        assert_eq!(PasswordPolicy{hex: true, length: 32, ..Default::default()}.entropy(None), 128.0);
+    }
+
+    #[test]
+    fn otp() {
+        use otp::{hotp, Algorithm, Totp};
+
+        // RFC 4226 appendix D
+        let key = b"12345678901234567890";
+        let codes = ["755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583", "399871", "520489"];
+        for (counter, code) in codes.iter().enumerate() {
+            assert_eq!(hotp(key, Algorithm::Sha1, counter as u64, 6), *code);
+        }
+
+        // RFC 6238 appendix B
+        let totp = |key: &[u8], algorithm, start| {
+            let mut t = Totp::new(key.to_vec());
+            t.algorithm = algorithm;
+            t.digits = 8;
+            t.start = start;
+            t
+        };
+        let sha1 = totp(key, Algorithm::Sha1, 0);
+        let sha256 = totp(b"12345678901234567890123456789012", Algorithm::Sha256, 0);
+        let sha512 = totp(b"1234567890123456789012345678901234567890123456789012345678901234", Algorithm::Sha512, 0);
+        let vectors = [
+            (59, "94287082", "46119246", "90693936"),
+            (1111111109, "07081804", "68084774", "25091201"),
+            (1111111111, "14050471", "67062674", "99943326"),
+            (1234567890, "89005924", "91819424", "93441116"),
+            (2000000000, "69279037", "90698825", "38618901"),
+            (20000000000, "65353130", "77737706", "47863826"),
+        ];
+        for &(t, a, b, c) in vectors.iter() {
+            assert_eq!(sha1.code(t), a);
+            assert_eq!(sha256.code(t), b);
+            assert_eq!(sha512.code(t), c);
+        }
+        assert_eq!(sha1.remaining(59), 1);
+        assert_eq!(sha1.remaining(60), 30);
+        assert_eq!(totp(key, Algorithm::Sha1, 50).code(59 + 50), "94287082");
+
+        let t = Totp::from_uri("otpauth://totp/ACME%20Co:john@example.com?secret=gezdgnbvgy3tqojqgezdgnbvgy3tqojq&issuer=ACME%20Co&algorithm=SHA256&digits=8&period=60").unwrap();
+        assert_eq!(t.key, key.to_vec());
+        assert_eq!((t.algorithm, t.digits, t.period()), (Algorithm::Sha256, 8, 60));
+        assert_eq!(Totp::from_uri("otpauth://totp/x?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap(), Totp::new(key.to_vec()));
+        for bad in &["https://totp/x?secret=GE", "otpauth://hotp/x?secret=GE&counter=1", "otpauth://totp/x?issuer=y",
+                     "otpauth://totp/x?secret=GE1", "otpauth://totp/x?secret=GE&algorithm=MD5", "otpauth://totp/x?secret=GE&digits=0"] {
+            assert!(Totp::from_uri(bad).is_err(), "{}", bad);
+        }
+
+        let mut i = titled("otp");
+        assert!(i.totp().is_none());
+        i.set_totp(&t);
+        match i.get(item::Kind::TwoFactorKey) {
+            Some(&item::Data::Sealed(_)) => (),
+            d => panic!("Unexpected two-factor key {:?}", d),
+        }
+        assert_eq!(i.totp(), Some(t));
+
+        i.set_totp(&sha1);
+        assert_eq!(i.get(item::Kind::TotpConfig), None);
+        assert_eq!(i.get(item::Kind::TotpLength), Some(&item::Data::Byte(8)));
+        assert_eq!(i.totp(), Some(sha1));
+
+        // corrupt lengths and time steps fall back to the default
+        i.insert(item::Kind::TotpLength, &item::Data::Byte(200));
+        i.insert(item::Kind::TotpTimeStep, &item::Data::Byte(0));
+        assert_eq!(i.totp().unwrap().digits, 6);
+        assert_eq!(i.totp().unwrap().period(), 30);
+        assert!(Totp::from_uri("otpauth://totp/x?secret=GE&period=0").is_err());
+
+        let mut t = Totp::new(key.to_vec());
+        assert!(!t.set_period(0));
+        assert_eq!(t.remaining(59), 1);
+        assert_eq!(::otp::hotp(b"12345678901234567890", ::otp::Algorithm::Sha1, 0, 20), "00000000001284755224");
+    }
+
+    #[test]
+    fn references() {
+        use reference::{Reference, Target};
+
+        let u = item::Uuid::parse("0123456789abcdef0123456789ABCDEF").unwrap();
+        assert_eq!(item::Uuid::parse(&u.to_string()), Some(u));
+        assert_eq!(u.to_hex(), "0123456789abcdef0123456789abcdef");
+
+        assert_eq!(Reference::parse("[[0123456789abcdef0123456789abcdef]]"), Some(Reference::Alias(Target::Uuid(u))));
+        assert_eq!(Reference::parse("[~0123456789abcdef0123456789abcdef~]"), Some(Reference::Shortcut(Target::Uuid(u))));
+        let name = |g: &str, t: &str, u: &str| Target::Name(g.to_string(), t.to_string(), u.to_string());
+        assert_eq!(Reference::parse("[[g:t:u:v]]"), Some(Reference::Alias(name("g", "t", "u:v"))));
+        assert_eq!(Reference::parse("[[g:t]]"), Some(Reference::Alias(name("g", "t", ""))));
+        assert_eq!(Reference::parse("[[t]]"), Some(Reference::Alias(name("", "t", ""))));
+        for p in &["password", "[[]]", "[[t", "t]]", "[~t]]", "\u{e9}[[\u{e9}"] {
+            assert_eq!(Reference::parse(p), None, "{}", p);
+        }
+        let r = Reference::Shortcut(Target::Uuid(u));
+        assert_eq!(Reference::parse(&r.to_string()), Some(r));
+
+        let mut kc = ::keychain::V3::new("references.psafe3");
+        kc.set_update_times(false);
+        let entry = |group: &str, title: &str, user: &str, password: &str| {
+            let mut i = titled(title);
+            i.insert(item::Kind::Group, &item::Data::Text(group.to_string()));
+            i.insert(item::Kind::Username, &item::Data::Text(user.to_string()));
+            i.insert(item::Kind::Password, &item::Data::Text(password.to_string()));
+            i
+        };
+        let password = |kc: &::keychain::V3, i: &item::Item| {
+            kc.resolve(i, item::Kind::Password).map(|b| b.reveal(item::Kind::Password, |d| d.unwrap().to_string()))
+        };
+
+        let mut base = entry("g", "base", "user", "secret");
+        base.insert(item::Kind::Notes, &item::Data::Text("notes".to_string()));
+        kc.insert(base);
+        let base_uuid = kc.iter().next().unwrap().uuid().unwrap();
+        kc.insert(entry("g", "alias", "me", &Reference::Alias(Target::Uuid(base_uuid)).to_string()));
+        kc.insert(entry("g", "by name", "me", "[[g:base:user]]"));
+        kc.insert(entry("g", "shortcut", "you", &format!("[~{}~]", base_uuid.to_hex())));
+        kc.insert(entry("g", "chain", "", "[[g:shortcut]]"));
+        kc.insert(entry("g", "broken", "", "[[g:missing]]"));
+        kc.insert(entry("g", "loop1", "", "[~g:loop2~]"));
+        kc.insert(entry("g", "loop2", "", "[~g:loop1~]"));
+
+        fn get<'a>(kc: &'a ::keychain::V3, title: &str) -> &'a item::Item {
+            return kc.find(&format!("^g\\.{}$", title)).unwrap()[0];
+        }
+        for title in &["alias", "by name", "shortcut", "chain"] {
+            assert_eq!(password(&kc, get(&kc, title)).unwrap(), "secret", "{}", title);
+        }
+
+        // aliases only take the password, shortcuts everything but their own fields
+        let alias = get(&kc, "alias");
+        assert!(::std::ptr::eq(kc.resolve(alias, item::Kind::Username).unwrap(), alias));
+        assert!(kc.resolve(alias, item::Kind::Notes).unwrap().get(item::Kind::Notes).is_none());
+
+        let shortcut = get(&kc, "shortcut");
+        let fields: Vec<(item::Kind, String)> = kc.resolved_fields(shortcut).unwrap().iter()
+            .filter(|f| f.kind() != item::Kind::UUID)
+            .map(|f| (f.kind(), f.reveal(|d| d.to_string())))
+            .collect();
+        assert_eq!(fields, vec![
+            (item::Kind::Title, "shortcut".to_string()),
+            (item::Kind::Group, "g".to_string()),
+            (item::Kind::Username, "you".to_string()),
+            (item::Kind::Password, "secret".to_string()),
+            (item::Kind::Notes, "notes".to_string()),
+        ]);
+
+        match password(&kc, get(&kc, "broken")) {
+            Err(::error::Error::BrokenReference(ref r)) if r == "g:missing:" => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        match password(&kc, get(&kc, "loop1")) {
+            Err(::error::Error::ReferenceCycle(ref n)) if n == "g.loop1" => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert!(kc.resolved_fields(get(&kc, "loop2")).is_err());
+        assert_eq!(kc.resolve(get(&kc, "loop1"), item::Kind::Title).unwrap().name(), "g.loop1");
+    }
+
+    #[test]
+    fn corrupt_field() {
+        let mut plain = plaintext(&[unknown_item()]);
+        plain.truncate(40);
+
+        let mut mac = crypto::HMAC::new(&[0; 32]);
+        let mut c = Cursor::new(&plain[..]);
+        match item::parse(&mut mac, &item::DATA, &mut c) {
+            Err(::error::Error::CorruptField{offset: 32}) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn query() {
+        let mut kc = ::keychain::V3::open("simple.psafe3", "bogus12345").expect("Can't open database");
+
+        let names: Vec<String> = kc.find("test\\.").expect("Can't query").iter().map(|i| i.name()).collect();
+        assert_eq!(names, vec!["Test.Test One", "Test.Test Nine", "Test.Test One"]);
+        assert_eq!(kc.find("").expect("Can't query").len(), 9);
+        assert!(kc.find("(").is_err());
+
+        let uuid = kc.find("four").expect("Can't query")[0].uuid().expect("Missing UUID");
+        kc.get_mut(&uuid).expect("Can't find entry").insert(item::Kind::Title, &item::Data::Text("Test 4".to_string()));
+        assert_eq!(kc.get(&uuid).expect("Can't find entry").name(), "Test 4");
+
+        assert!(kc.remove(&uuid).is_some());
+        assert!(kc.get(&uuid).is_none());
+        assert_eq!(kc.len(), 8);
+    }
+
+    #[test]
+    fn streams() {
+        let f = fs::File::open("simple.psafe3").expect("Can't open database");
+        let mut kc = ::keychain::V3::from_reader(f, "bogus12345").expect("Can't read database");
+        assert_eq!(kc.len(), 9);
+
+        let mut d = Vec::new();
+        kc.write_to(&mut d, "secret").expect("Can't write database");
+
+        let kc = ::keychain::V3::from_reader(&d[..], "secret").expect("Can't read database");
+        assert_eq!(kc.len(), 9);
+        assert!(::keychain::V3::from_reader(&d[..d.len() - 1], "secret").is_err());
+
+        let mut kc = ::keychain::V3::from_reader(Cursor::new(d), "secret").expect("Can't read database");
+        assert!(kc.save("secret").is_err());
+    }
+
+    #[test]
+    fn backups() {
+        let path = "backups.psafe3";
+        let backup = |n| ::keychain::backup_path(::std::path::Path::new(path), n);
+
+        for n in 1..4 {
+            fs::remove_file(backup(n)).ok();
+        }
+        fs::copy("simple.psafe3", path).expect("Can't copy database");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600)).expect("Can't change permissions");
+        }
+
+        let mut kc = ::keychain::V3::open(path, "bogus12345").expect("Can't open database");
+        kc.set_backups(2);
+        for n in 0..3 {
+            let mut i = item::new();
+            i.insert(item::Kind::Title, &item::Data::Text(format!("backup {}", n)));
+            kc.insert(i);
+            kc.save("bogus12345").expect("Can't save database");
+        }
+
+        assert_eq!(backup(1).to_str(), Some("backups_001.ibak"));
+        assert_eq!(::keychain::V3::open(path, "bogus12345").expect("Can't open database").len(), 12);
+        assert_eq!(::keychain::V3::open(backup(1).to_str().unwrap(), "bogus12345").expect("Can't open backup").len(), 11);
+        assert_eq!(::keychain::V3::open(backup(2).to_str().unwrap(), "bogus12345").expect("Can't open backup").len(), 10);
+        assert!(!backup(3).exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(path).expect("Can't stat database").permissions().mode() & 0o777, 0o600);
+        }
+
+        for e in fs::read_dir(".").expect("Can't list directory") {
+            let name = e.expect("Can't list directory").file_name().into_string().unwrap();
+            assert!(!(name.starts_with(path) && name.ends_with(".tmp")));
+        }
+    }
+
+    #[test]
+    fn iterations() {
+        let path = "iterations.psafe3";
+        fs::remove_file(path).ok();
+
+        let mut kc = ::keychain::V3::new(path);
+        assert_eq!(kc.iterations(), 100000);
+
+        kc.set_iterations(10);
+        assert_eq!(kc.iterations(), ::keychain::MIN_ITER);
+
+        kc.set_iterations(3000);
+        kc.save("bogus12345").expect("Can't save database");
+        assert_eq!(::keychain::V3::open(path, "bogus12345").expect("Can't open database").iterations(), 3000);
+
+        assert!(::keychain::calibrate(0) == ::keychain::MIN_ITER);
+        assert!(::keychain::calibrate(10) >= ::keychain::MIN_ITER);
+        assert_eq!(::keychain::calibrate(u64::max_value()), u32::max_value());
+    }
+
+    #[test]
+    fn locking() {
+        let path = "locking.psafe3";
+        let plk = ::lock::lock_path(::std::path::Path::new(path));
+        fs::copy("simple.psafe3", path).expect("Can't copy database");
+        fs::remove_file(&plk).ok();
+
+        {
+            let kc = ::keychain::V3::open_locked(path, "bogus12345").expect("Can't open database");
+            assert!(!kc.read_only());
+            assert_eq!(plk.to_str(), Some("locking.plk"));
+
+            let owner = fs::read_to_string(&plk).expect("Can't read lock");
+            assert!(owner.ends_with(&format!(":{}", ::std::process::id())));
+
+            match ::lock::Lock::acquire(path) {
+                Err(::error::Error::Locked(o)) => assert_eq!(o.to_string(), owner),
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+        assert!(!plk.exists());
+
+        fs::write(&plk, "somebody@elsewhere:1").expect("Can't write lock");
+        let mut kc = ::keychain::V3::open_locked(path, "bogus12345").expect("Can't open database");
+        assert!(kc.read_only());
+        assert_eq!(kc.locked_by().expect("No lock owner").host, "elsewhere");
+        match kc.save("bogus12345") {
+            Err(::error::Error::ReadOnly) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        drop(kc);
+        assert!(plk.exists());
+
+        fs::write(&plk, format!("somebody@{}:{}", ::keychain::hostname(), u32::max_value())).expect("Can't write lock");
+        match ::keychain::V3::open_locked(path, "bogus12345") {
+            Err(::error::Error::StaleLock(o)) => assert_eq!(o.user, "somebody"),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        ::lock::break_lock(path).expect("Can't break lock");
+        assert!(!plk.exists());
+
+        // init is always running, even if it belongs to somebody else
+        #[cfg(unix)]
+        {
+            fs::write(&plk, format!("somebody@{}:1", ::keychain::hostname())).expect("Can't write lock");
+            match ::keychain::V3::open_locked(path, "bogus12345") {
+                Ok(ref kc) if kc.read_only() => (),
+                r => panic!("Unexpected result {:?}", r),
+            }
+            ::lock::break_lock(path).expect("Can't break lock");
+        }
+    }
+
+    fn titled(title: &str) -> item::Item {
+        let mut i = item::new();
+        i.insert(item::Kind::Title, &item::Data::Text(title.to_string()));
+        i
+    }
+
+    #[test]
+    fn conflicts() {
+        let path = "conflicts.psafe3";
+        fs::copy("simple.psafe3", path).expect("Can't copy database");
+
+        let mut a = ::keychain::V3::open(path, "bogus12345").expect("Can't open database");
+        let mut b = ::keychain::V3::open(path, "bogus12345").expect("Can't open database");
+
+        // b adds one entry, changes another one and deletes the third one
+        let changed = b.find("four").expect("Can't query")[0].uuid().expect("Missing UUID");
+        let deleted = b.find("five").expect("Can't query")[0].uuid().expect("Missing UUID");
+        {
+            let i = b.get_mut(&changed).expect("Can't find entry");
+            i.insert(item::Kind::Title, &item::Data::Text("Test 4".to_string()));
+            i.insert(item::Kind::ModifyTime, &item::Data::Time(item::Time::from_secs(1500000000)));
+        }
+        b.remove(&deleted);
+        b.insert(titled("from b"));
+        b.save("bogus12345").expect("Can't save database");
+
+        a.insert(titled("from a"));
+        let before = fs::read(path).expect("Can't read database");
+        match a.save("bogus12345") {
+            Err(::error::Error::Modified) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert_eq!(fs::read(path).expect("Can't read database"), before);
+
+        a.save_with("bogus12345", ::keychain::Conflict::Merge).expect("Can't save database");
+        let kc = ::keychain::V3::open(path, "bogus12345").expect("Can't open database");
+        assert_eq!(kc.len(), 10);
+        assert!(kc.get(&deleted).is_none());
+        assert_eq!(kc.find("from").expect("Can't query").len(), 2);
+        assert_eq!(kc.get(&changed).expect("Can't find entry").name(), "Test 4");
+
+        // nothing changed on disk since the last save
+        a.insert(titled("again"));
+        a.save("bogus12345").expect("Can't save database");
+
+        b.save_with("bogus12345", ::keychain::Conflict::Overwrite).expect("Can't save database");
+        let kc = ::keychain::V3::open(path, "bogus12345").expect("Can't open database");
+        assert_eq!(kc.len(), 9);
+    }
+
+    fn parse_all(data: &[u8]) -> Result<Vec<item::Item>, ::error::Error> {
+        crypto::init();
+
+        let mut mac = crypto::HMAC::new(&[0; 32]);
+        let mut c = Cursor::new(data);
+        let mut v = Vec::new();
+        loop {
+            match item::parse(&mut mac, &item::DATA, &mut c)? {
+                Some(i) => v.push(i),
+                None => return Ok(v),
+            }
+        }
+    }
+
+    fn eof_pos(d: &[u8]) -> usize {
+        d.windows(16).position(|w| w == b"PWS3-EOFPWS3-EOF").expect("No EOF marker")
+    }
+
+    // inputs that used to crash the parser
+    #[test]
+    fn fuzz_regressions() {
+        // field is shorter than its length
+        match parse_all(&[4, 0, 0, 0, 0x07, 1, 2]) {
+            Err(::error::Error::CorruptField{offset: 0}) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // length doesn't fit the remaining data
+        match parse_all(&[0xff, 0xff, 0xff, 0xff, 0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]) {
+            Err(::error::Error::CorruptField{offset: 0}) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // integer field that is too short
+        let mut d = vec![1, 0, 0, 0, 0x07, 0xaa];
+        d.resize(16, 0);
+        d.extend_from_slice(&[0, 0, 0, 0, 0xff]);
+        d.resize(32, 0);
+        let items = parse_all(&d).expect("Can't parse items");
+        assert_eq!(items[0].get(item::Kind::Unknown(0x07)), Some(&item::Data::Raw(vec![0xaa])));
+
+        // item without End field
+        match parse_all(&[0, 0, 0, 0, 0x03]) {
+            Err(::error::Error::Truncated) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // nothing after the EOF marker
+        match corrupt("fuzz1.psafe3", &|d| { let n = eof_pos(d); d.truncate(n + 16); }) {
+            ::error::Error::MissingEof => (),
+            e => panic!("Unexpected error {:?}", e),
+        }
+
+        // encrypted data is not a multiple of the block size
+        match corrupt("fuzz2.psafe3", &|d| { let n = eof_pos(d); d.remove(n - 1); }) {
+            ::error::Error::BadLength => (),
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn integrity() {
+        // tag, salt, iterations, password hash, B1-B4
+        let iv = 4 + 32 + 4 + 32 + 32 + 32;
+
+        // flips a bit of the version field data in the first block
+        match corrupt("integrity1.psafe3", &|d| d[iv + 5] ^= 1) {
+            ::error::Error::HmacMismatch => (),
+            e => panic!("Unexpected error {:?}", e),
+        }
+
+        match corrupt("integrity2.psafe3", &|d| d.push(0)) {
+            ::error::Error::MissingEof => (),
+            e => panic!("Unexpected error {:?}", e),
+        }
+
+        // EOF marker somewhere in the middle doesn't count
+        match corrupt("integrity3.psafe3", &|d| d.extend_from_slice(&[0; 16])) {
+            ::error::Error::MissingEof => (),
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn sealed() {
+        let mut i = item::new();
+        i.insert(item::Kind::Title, &item::Data::Text("sealed".to_string()));
+        i.insert(item::Kind::Password, &item::Data::Text("my password".to_string()));
+        i.insert(item::Kind::Notes, &item::Data::Text(String::new()));
+
+        // sensitive fields are only accessible through reveal
+        match i.get(item::Kind::Password) {
+            Some(&item::Data::Sealed(ref s)) => assert_eq!(s.len(), 11),
+            d => panic!("Unexpected data {:?}", d),
+        }
+        assert!(!format!("{:?}", i).contains("my password"));
+        assert_eq!(i.iter().find(|f| f.kind() == item::Kind::Password).unwrap().to_string(), "<hidden>");
+
+        i.reveal(item::Kind::Password, |d| assert_eq!(d, Some(&item::Data::Text("my password".to_string()))));
+        i.reveal(item::Kind::Notes, |d| assert_eq!(d, Some(&item::Data::Text(String::new()))));
+        i.reveal(item::Kind::Title, |d| assert_eq!(d, Some(&item::Data::Text("sealed".to_string()))));
+        i.reveal(item::Kind::Email, |d| assert_eq!(d, None));
+
+        // and are written out decrypted
+        let d = plaintext(&[i]);
+        let items = parse_all(&d).unwrap();
+        items[0].reveal(item::Kind::Password, |d| assert_eq!(d, Some(&item::Data::Text("my password".to_string()))));
+    }
+
+    #[test]
+    fn secrets() {
+        use secret::{self, SecretKey, SecretString};
+
+        let mut b = b"bogus12345".to_vec();
+        secret::wipe(&mut b);
+        assert_eq!(b, vec![0; 10]);
+
+        let password = SecretString::new("bogus12345".to_string());
+        assert_eq!(&*password, "bogus12345");
+        assert!(password == SecretString::from("bogus12345".to_string()));
+        assert_eq!(format!("{:?}", password), "SecretString(..)");
+
+        let key = SecretKey::new();
+        assert_eq!(&key[..], &[0; 32][..]);
+        assert_eq!(format!("{:?}", key), "SecretKey(..)");
+
+        // no core dumps or ptrace while a database is unlocked
+        let kc = ::keychain::V3::open("simple.psafe3", "bogus12345").unwrap();
+        #[cfg(target_os = "linux")]
+        assert_eq!(unsafe { ::libc::prctl(::libc::PR_GET_DUMPABLE, 0, 0, 0, 0) }, 0);
+        drop(kc);
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len() / 2).map(|i| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn vectors() {
+        crypto::init();
+
+        assert!(crypto::ct_eq(b"bogus12345", b"bogus12345"));
+        assert!(!crypto::ct_eq(b"bogus12345", b"bogus12346"));
+        assert!(!crypto::ct_eq(b"bogus12345", b"bogus1234"));
+
+        // SHA-256 applied 2048 times to SHA-256("bogus12345" | salt)
+        let stretched = crypto::stretch("bogus12345", &[0x11; 32], 2048);
+        assert_eq!(&stretched[..], &hex("849c095487a2f685889d777735e935da8d24532ff79bdd992278ee108dadc34a")[..]);
+        assert_eq!(&crypto::sha256(&stretched[..])[..], &hex("77d674494ffeac392af256380da5947f6eb47e795e945fd06cefefb95bebb2a0")[..]);
+
+        // password hash stored in a file written by Password Safe
+        let d = fs::read("simple.psafe3").unwrap();
+        let iter = d[36] as u32 | (d[37] as u32) << 8 | (d[38] as u32) << 16 | (d[39] as u32) << 24;
+        let stretched = crypto::stretch("bogus12345", &d[4..36], iter);
+        assert!(crypto::ct_eq(&crypto::sha256(&stretched[..]), &d[40..72]));
+
+        // Twofish 256-bit key known answers from the Twofish paper
+        let zero = [0; 32];
+        let ct = crypto::encrypt_block_ecb(&zero, &zero).unwrap();
+        assert_eq!(&ct[..], &hex("57ff739d4dc92c1bd7fc01700cc8216f57ff739d4dc92c1bd7fc01700cc8216f")[..]);
+        assert_eq!(&crypto::decrypt_block_ecb(&ct, &zero).unwrap()[..], &zero[..]);
+
+        let key = hex("0123456789abcdeffedcba987654321000112233445566778899aabbccddeeff");
+        let ct = crypto::encrypt_block_ecb(&zero, &key).unwrap();
+        assert_eq!(&ct[..16], &hex("37527be0052334b89f0cfccae87cfa20")[..]);
+
+        // CBC chains E(0) into the second block: E(E(0)) with zero key
+        let mut d = vec![0; 32];
+        crypto::encrypt_inplace(&mut d, &zero, &[0; 16]).unwrap();
+        assert_eq!(d, hex("57ff739d4dc92c1bd7fc01700cc8216fd43bb7556ea32e46f2a282b7d45b4e0d"));
+        crypto::decrypt_inplace(&mut d, &zero, &[0; 16]).unwrap();
+        assert_eq!(d, vec![0; 32]);
+    }
+
+    // same keys, salt and data have to give the same file with every backend
+    #[test]
+    fn backends() {
+        use byteorder::{LittleEndian, WriteBytesExt};
+
+        macro_rules! file {
+            ($backend:ident, $plain:expr) => {{
+                use crypto::$backend as c;
+
+                let salt: [u8; 32] = [0x11; 32];
+                let k: [u8; 32] = [0x22; 32];
+                let l: [u8; 32] = [0x33; 32];
+                let iv: [u8; 16] = [0x44; 16];
+                let iter = 2048;
+
+                c::init();
+                let stretched = c::stretch("bogus12345", &salt, iter);
+
+                let mut f = Vec::new();
+                f.extend_from_slice(b"PWS3");
+                f.extend_from_slice(&salt);
+                f.write_u32::<LittleEndian>(iter).unwrap();
+                f.extend_from_slice(&c::sha256(&stretched[..]));
+                f.extend_from_slice(&c::encrypt_block_ecb(&k, &stretched[..]).unwrap());
+                f.extend_from_slice(&c::encrypt_block_ecb(&l, &stretched[..]).unwrap());
+                f.extend_from_slice(&iv);
+
+                let mut mac = c::HMAC::new(&l);
+                mac.update(&$plain);
+
+                let mut d = $plain.clone();
+                c::encrypt_inplace(&mut d, &k, &iv).unwrap();
+                f.extend_from_slice(&d);
+                f.extend_from_slice(b"PWS3-EOFPWS3-EOF");
+                f.extend_from_slice(&mac.get_mac());
+
+                c::decrypt_inplace(&mut d, &k, &iv).unwrap();
+                assert_eq!(d, $plain);
+                assert_eq!(&c::decrypt_block_ecb(&f[72..104], &stretched[..]).unwrap()[..], &k[..]);
+                f
+            }}
+        }
+
+        let plain = plaintext(&[unknown_item(), titled("backends")]);
+        let digest = |f: Vec<u8>| crypto::sha256(&f).iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        #[cfg(feature = "rustcrypto")]
+        assert_eq!(digest(file!(rustcrypto, plain)), "5a4aa9473198747899d1207a29a3110de2b0a2b901af6d6d95e62aa6c1837a4d");
+        #[cfg(feature = "gcrypt")]
+        assert_eq!(digest(file!(libgcrypt, plain)), "5a4aa9473198747899d1207a29a3110de2b0a2b901af6d6d95e62aa6c1837a4d");
+    }
 }